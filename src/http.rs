@@ -1,2 +1,4 @@
+pub mod client;
+pub mod connection;
 pub mod message;
 pub mod router;
@@ -0,0 +1,535 @@
+use std::{collections::HashMap, fmt, io, sync::Mutex, time::Duration};
+
+use anyhow::{bail, Context, Result};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::timeout,
+};
+
+use super::message::{
+    message::{content_length, header_value},
+    request::{Method, Request},
+    response::{Redirection, Response, Status},
+};
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_REDIRECTS: usize = 10;
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+// larger response bodies are refused instead of being buffered
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// HTTP/1.1 client speaking the same message types as the server
+// idle connections are kept per host and re-used for subsequent requests
+pub struct Client {
+    pool: Mutex<HashMap<String, Vec<TcpStream>>>,
+    connect_timeout: Duration,
+    timeout: Duration,
+    max_redirects: usize,
+    max_idle_per_host: usize,
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Client {
+    pub fn new() -> Self {
+        Self {
+            pool: Mutex::new(HashMap::new()),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            timeout: DEFAULT_TIMEOUT,
+            max_redirects: DEFAULT_MAX_REDIRECTS,
+            max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+        }
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    // applies to every single exchange (one per redirect hop)
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    // 0 disables redirect following, redirects are then returned as is
+    pub fn with_max_redirects(mut self, max_redirects: usize) -> Self {
+        self.max_redirects = max_redirects;
+        self
+    }
+
+    pub fn with_max_idle_per_host(mut self, max_idle_per_host: usize) -> Self {
+        self.max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    // sends a request to host (host[:port]) and follows redirects
+    pub async fn send(&self, host: &str, mut request: Request) -> Result<Response> {
+        let mut host = host.to_string();
+
+        for _ in 0..self.max_redirects {
+            let response = self.exchange(&host, request.clone()).await?;
+
+            let Status::Redirection(redirection) = response.start_line.status() else {
+                return Ok(response);
+            };
+            let Some(location) = response.header("Location") else {
+                return Ok(response);
+            };
+
+            let (next_host, target) = resolve_location(&host, location)?;
+
+            // 303 always, and 301/302 historically, turn the follow-up into a GET
+            let method = request.start_line.method;
+            if redirection == Redirection::SeeOther
+                || (method == Method::Post
+                    && matches!(
+                        redirection,
+                        Redirection::MovedPermanently | Redirection::Found
+                    ))
+            {
+                request.start_line.method = Method::Get;
+                request.body = None;
+                request
+                    .headers
+                    .retain(|k, _| !k.eq_ignore_ascii_case("Content-Length"));
+            }
+
+            // the Host header belongs to the previous hop
+            if next_host != host {
+                request
+                    .headers
+                    .retain(|k, _| !k.eq_ignore_ascii_case("Host"));
+            }
+
+            request.start_line.target = target;
+            host = next_host;
+        }
+
+        let response = self.exchange(&host, request).await?;
+        if self.max_redirects > 0 && matches!(response.start_line.status(), Status::Redirection(_))
+        {
+            bail!("too many redirects");
+        }
+
+        Ok(response)
+    }
+
+    async fn exchange(&self, host: &str, mut request: Request) -> Result<Response> {
+        if request.header("Host").is_none() {
+            request.headers.insert("Host".to_string(), host.to_string());
+        }
+        // without a length the server would take the body for the start of the next request
+        if let Some(body) = &request.body {
+            if request.header("Content-Length").is_none() {
+                let length = body.len().to_string();
+                request.headers.insert("Content-Length".to_string(), length);
+            }
+        }
+
+        let close = request
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        let idempotent = request.start_line.method.is_idempotent();
+        let raw = Into::<String>::into(request);
+        let address = socket_address(host);
+
+        // a pooled connection might have been closed by the server in the meantime,
+        // in that case idempotent requests are retried once on a fresh connection
+        // anything else might have reached the server, so it is not sent again
+        if let Some(mut stream) = self.checkout(&address) {
+            match self.roundtrip(&mut stream, &raw).await {
+                Ok(response) => {
+                    self.checkin(address, stream, close, &response);
+                    return Ok(response);
+                }
+                Err(e) if !(idempotent && e.is::<StaleConnection>()) => return Err(e),
+                Err(_) => {}
+            }
+        }
+
+        let mut stream = timeout(self.connect_timeout, TcpStream::connect(&address))
+            .await
+            .context("connect timed out")?
+            .context("could not connect")?;
+
+        let response = self.roundtrip(&mut stream, &raw).await?;
+        self.checkin(address, stream, close, &response);
+
+        Ok(response)
+    }
+
+    async fn roundtrip(&self, stream: &mut TcpStream, raw: &str) -> Result<Response> {
+        timeout(self.timeout, async {
+            if stream.write_all(raw.as_bytes()).await.is_err() {
+                bail!(StaleConnection);
+            }
+            read_response(stream).await
+        })
+        .await
+        .context("request timed out")?
+    }
+
+    fn checkout(&self, address: &str) -> Option<TcpStream> {
+        let mut pool = self.pool.lock().expect("could not lock pool");
+        pool.get_mut(address).and_then(|streams| streams.pop())
+    }
+
+    fn checkin(&self, address: String, stream: TcpStream, close: bool, response: &Response) {
+        let closed_by_server = response
+            .header("Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        if close || closed_by_server {
+            return;
+        }
+
+        let mut pool = self.pool.lock().expect("could not lock pool");
+        let streams = pool.entry(address).or_default();
+        if streams.len() < self.max_idle_per_host {
+            streams.push(stream);
+        }
+    }
+}
+
+// the connection was closed before the request was sent or any part of the response arrived
+#[derive(Debug)]
+struct StaleConnection;
+
+impl fmt::Display for StaleConnection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "connection closed before a response was received")
+    }
+}
+
+impl std::error::Error for StaleConnection {}
+
+async fn read_response(stream: &mut TcpStream) -> Result<Response> {
+    let mut buffer = Vec::new();
+    let mut chunk = [0; 1024];
+
+    let header_end = loop {
+        if let Some(pos) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+            break pos + 4;
+        }
+
+        let bytes_read = match stream.read(&mut chunk).await {
+            Ok(0) if buffer.is_empty() => bail!(StaleConnection),
+            Ok(0) => bail!("connection closed in the middle of a response"),
+            Err(e) if buffer.is_empty() && e.kind() == io::ErrorKind::ConnectionReset => {
+                bail!(StaleConnection)
+            }
+            result => result?,
+        };
+        buffer.extend_from_slice(&chunk[..bytes_read]);
+    };
+
+    let head = std::str::from_utf8(&buffer[..header_end])?;
+    if header_value(head, "Transfer-Encoding").is_some() {
+        bail!("transfer encodings are not supported");
+    }
+
+    match content_length(head)? {
+        Some(length) => {
+            let end = match header_end.checked_add(length) {
+                Some(end) if length <= MAX_BODY_SIZE => end,
+                _ => bail!("response body is too large"),
+            };
+            while buffer.len() < end {
+                let bytes_read = stream.read(&mut chunk).await?;
+                if bytes_read == 0 {
+                    bail!("connection closed before the body was received");
+                }
+                buffer.extend_from_slice(&chunk[..bytes_read]);
+            }
+            buffer.truncate(end);
+        }
+        // without a length the body is delimited by the server closing the connection
+        None if header_value(head, "Connection")
+            .is_some_and(|v| v.eq_ignore_ascii_case("close")) =>
+        {
+            let limit = header_end + MAX_BODY_SIZE;
+            (&mut *stream)
+                .take((limit + 1 - buffer.len()) as u64)
+                .read_to_end(&mut buffer)
+                .await?;
+            if buffer.len() > limit {
+                bail!("response body is too large");
+            }
+        }
+        None => buffer.truncate(header_end),
+    }
+
+    String::from_utf8(buffer)?.try_into()
+}
+
+// plain hosts default to port 80
+fn socket_address(host: &str) -> String {
+    if host.contains(':') {
+        host.to_string()
+    } else {
+        format!("{}:80", host)
+    }
+}
+
+// returns the host and target a Location header points to
+fn resolve_location(host: &str, location: &str) -> Result<(String, String)> {
+    if let Some(rest) = location.strip_prefix("http://") {
+        let (authority, target) = match rest.find('/') {
+            Some(pos) => rest.split_at(pos),
+            None => (rest, "/"),
+        };
+        return Ok((authority.to_string(), target.to_string()));
+    }
+
+    if location.starts_with('/') {
+        return Ok((host.to_string(), location.to_string()));
+    }
+
+    bail!("unsupported redirect location {}", location)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use crate::http::message::{
+        message::{HttpMessage, Version},
+        request::{Method, Request, RequestLine},
+        response::{Redirection, Response, Status, StatusLine},
+    };
+
+    use super::{resolve_location, Client};
+
+    // serves requests on localhost, keeping connections open until the client closes them
+    async fn serve(accepted: Arc<AtomicUsize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                accepted.fetch_add(1, Ordering::SeqCst);
+
+                tokio::spawn(async move {
+                    let mut buffer = [0; 1024];
+                    loop {
+                        let bytes_read = socket.read(&mut buffer).await.unwrap_or(0);
+                        if bytes_read == 0 {
+                            return;
+                        }
+
+                        let raw = String::from_utf8(buffer[..bytes_read].to_vec()).unwrap();
+                        let request = Request::try_from(raw).unwrap();
+                        let response: String = respond(&request).into();
+                        socket.write_all(response.as_bytes()).await.unwrap();
+
+                        // closes without telling the client, like servers dropping idle connections
+                        if request.start_line.target == "/bye" {
+                            return;
+                        }
+                    }
+                });
+            }
+        });
+
+        address
+    }
+
+    fn respond(request: &Request) -> Response {
+        match request.start_line.target.as_str() {
+            "/old" => HttpMessage::new(
+                StatusLine::new(Version::Http1_1, Status::Redirection(Redirection::Found)),
+                HashMap::from([("Location".to_string(), "/new".to_string())]),
+                None,
+            ),
+            "/loop" => HttpMessage::new(
+                StatusLine::new(
+                    Version::Http1_1,
+                    Status::Redirection(Redirection::TemporaryRedirect),
+                ),
+                HashMap::from([("Location".to_string(), "/loop".to_string())]),
+                None,
+            ),
+            "/moved" => HttpMessage::new(
+                StatusLine::new(
+                    Version::Http1_1,
+                    Status::Redirection(Redirection::TemporaryRedirect),
+                ),
+                HashMap::from([("Location".to_string(), "/echo".to_string())]),
+                None,
+            ),
+            "/echo" => {
+                let body = request.body.clone().unwrap_or_default();
+                Response::ok(
+                    HashMap::from([("Content-Length".to_string(), body.len().to_string())]),
+                    Some(body),
+                )
+            }
+            "/busy" => HttpMessage::new(
+                StatusLine::new(Version::Http1_1, Status::Other(503)),
+                HashMap::from([("Content-Length".to_string(), "0".to_string())]),
+                None,
+            ),
+            target => Response::ok(
+                HashMap::from([("Content-Length".to_string(), target.len().to_string())]),
+                Some(target.to_string()),
+            ),
+        }
+    }
+
+    fn get(target: &str) -> Request {
+        request(Method::Get, target)
+    }
+
+    fn request(method: Method, target: &str) -> Request {
+        Request::new(
+            RequestLine::new(method, target.to_string(), Version::Http1_1),
+            HashMap::new(),
+            None,
+        )
+    }
+
+    #[tokio::test]
+    async fn reuses_connections() {
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let host = serve(Arc::clone(&accepted)).await;
+        let client = Client::new();
+
+        let first = client.send(&host, get("/first")).await.unwrap();
+        let second = client.send(&host, get("/second")).await.unwrap();
+
+        assert_eq!(first.body, Some("/first".to_string()));
+        assert_eq!(second.body, Some("/second".to_string()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn retries_stale_connections() {
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let host = serve(Arc::clone(&accepted)).await;
+        let client = Client::new();
+
+        // the pooled connection is closed, the GET is sent again on a new one
+        client.send(&host, get("/bye")).await.unwrap();
+        let response = client.send(&host, get("/again")).await.unwrap();
+        assert_eq!(response.body, Some("/again".to_string()));
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+
+        // a POST might have been processed, it is not sent again on a new connection
+        client.send(&host, get("/bye")).await.unwrap();
+        assert!(client
+            .send(&host, request(Method::Post, "/post"))
+            .await
+            .is_err());
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn follows_redirects() {
+        let host = serve(Arc::new(AtomicUsize::new(0))).await;
+        let client = Client::new();
+
+        let response = client.send(&host, get("/old")).await.unwrap();
+        assert_eq!(response.body, Some("/new".to_string()));
+
+        assert!(client.send(&host, get("/loop")).await.is_err());
+
+        let client = Client::new().with_max_redirects(0);
+        let response = client.send(&host, get("/old")).await.unwrap();
+        assert_eq!(
+            response.start_line.status(),
+            Status::Redirection(Redirection::Found)
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_content_length() {
+        let host = serve(Arc::new(AtomicUsize::new(0))).await;
+        let client = Client::new();
+        let post = |target| {
+            let mut request = request(Method::Post, target);
+            request.body = Some("hello".to_string());
+            request
+        };
+
+        let response = client.send(&host, post("/echo")).await.unwrap();
+        assert_eq!(response.body, Some("hello".to_string()));
+
+        // 307 keeps the body, and its length, for the next hop
+        let response = client.send(&host, post("/moved")).await.unwrap();
+        assert_eq!(response.body, Some("hello".to_string()));
+    }
+
+    #[tokio::test]
+    async fn refuses_large_bodies() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut buffer = [0; 1024];
+            let _ = socket.read(&mut buffer).await;
+            let head = format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+            socket.write_all(head.as_bytes()).await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        // would overflow when added to the length of the head
+        let client = Client::new().with_timeout(Duration::from_secs(1));
+        let error = client.send(&host, get("/")).await.err().unwrap();
+        assert!(error.to_string().contains("too large"));
+    }
+
+    #[tokio::test]
+    async fn unknown_status() {
+        let host = serve(Arc::new(AtomicUsize::new(0))).await;
+        let client = Client::new();
+
+        let response = client.send(&host, get("/busy")).await.unwrap();
+        assert_eq!(response.start_line.status(), Status::Other(503));
+    }
+
+    #[tokio::test]
+    async fn times_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        // accept but never answer
+        tokio::spawn(async move {
+            let (_socket, _) = listener.accept().await.unwrap();
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let client = Client::new().with_timeout(Duration::from_millis(100));
+        assert!(client.send(&host, get("/")).await.is_err());
+    }
+
+    #[test]
+    fn locations() {
+        assert_eq!(
+            resolve_location("a:1", "/b").unwrap(),
+            ("a:1".to_string(), "/b".to_string())
+        );
+        assert_eq!(
+            resolve_location("a:1", "http://c:2/d?e").unwrap(),
+            ("c:2".to_string(), "/d?e".to_string())
+        );
+        assert!(resolve_location("a:1", "https://c/d").is_err());
+    }
+}
@@ -0,0 +1,674 @@
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{bail, Result};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        watch, OwnedSemaphorePermit, Semaphore,
+    },
+    time::timeout,
+};
+
+use super::{
+    message::{
+        message::{content_length, header_value, Version},
+        request::Request,
+        response::{
+            ClientError, HttpError, IntoResponse, Response, ServerError, Status, Successful,
+        },
+    },
+    router::{BoxFuture, VirtualHosts},
+};
+
+// larger heads are most likely not HTTP at all
+const MAX_HEAD_SIZE: usize = 64 * 1024;
+// larger bodies are answered with 413 instead of being buffered
+const MAX_BODY_SIZE: usize = 16 * 1024 * 1024;
+
+// limits for persistent connections
+#[derive(Debug, Clone, Copy)]
+pub struct ConnectionConfig {
+    // requests served on a connection before it is closed
+    pub max_requests: u64,
+    // how long a connection may wait for its next request while no response is pending
+    pub idle_timeout: Duration,
+    // how long a request that started arriving may go without further bytes, answered with 408
+    pub request_timeout: Duration,
+    // requests read ahead of the response being written (pipelining),
+    // further requests are not read until a response was sent
+    pub max_pipelined: usize,
+    // whether pipelined requests are handled concurrently, otherwise one after another
+    // responses are written in request order either way
+    pub concurrent: bool,
+}
+
+impl Default for ConnectionConfig {
+    fn default() -> Self {
+        Self {
+            max_requests: 100,
+            idle_timeout: Duration::from_secs(5),
+            request_timeout: Duration::from_secs(5),
+            max_pipelined: 16,
+            concurrent: false,
+        }
+    }
+}
+
+// a request read from the connection whose response was not written yet
+struct Pending {
+    response: BoxFuture<'static, Response>,
+    version: Version,
+    keep_alive: bool,
+    // frees the slot for another pipelined request once the response is written
+    _permit: OwnedSemaphorePermit,
+}
+
+// logs connections dropped before they were done, i.e. aborted after the shutdown grace period
+// or unwound by a panicking handler
+struct Unfinished {
+    peer: SocketAddr,
+    // requests received but not answered yet
+    unanswered: Arc<AtomicUsize>,
+    done: bool,
+}
+
+impl Drop for Unfinished {
+    fn drop(&mut self) {
+        if self.done {
+            return;
+        }
+        if std::thread::panicking() {
+            println!(
+                "connection from {} failed while handling a request",
+                self.peer
+            );
+            return;
+        }
+
+        // idle connections stop on shutdown, so anything else was still receiving a request
+        match self.unanswered.load(Ordering::SeqCst) {
+            0 => println!(
+                "dropped connection from {} while receiving a request",
+                self.peer
+            ),
+            n => println!(
+                "dropped connection from {} with {} unanswered requests",
+                self.peer, n
+            ),
+        }
+    }
+}
+
+// serves the requests of a connection until the client or a limit closes it
+// requests are read while earlier ones are still being answered, see ConnectionConfig
+// once shutdown is set no further requests are read and the next response tells the client
+// the connection is closed, pipelined requests behind it are left for the client to retry
+pub async fn serve<R, W, S>(
+    reader: R,
+    writer: W,
+    peer: SocketAddr,
+    hosts: Arc<VirtualHosts<S>>,
+    config: ConnectionConfig,
+    shutdown: watch::Receiver<bool>,
+) where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    S: Clone + Send + Sync + 'static,
+{
+    let mut unfinished = Unfinished {
+        peer,
+        unanswered: Arc::default(),
+        done: false,
+    };
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let reading = read_requests(
+        reader,
+        peer,
+        hosts,
+        config,
+        Arc::clone(&unfinished.unanswered),
+        shutdown.clone(),
+        sender,
+    );
+    let writing = write_responses(
+        writer,
+        receiver,
+        Arc::clone(&unfinished.unanswered),
+        shutdown,
+    );
+    tokio::pin!(writing);
+
+    // the connection is done once the writer is, whatever the reader is waiting for
+    tokio::select! {
+        _ = &mut writing => {}
+        _ = reading => writing.await,
+    }
+    unfinished.done = true;
+
+    let unanswered = unfinished.unanswered.load(Ordering::SeqCst);
+    if unanswered > 0 {
+        println!(
+            "closed connection from {} with {} unanswered requests",
+            peer, unanswered
+        );
+    }
+}
+
+async fn read_requests<R, S>(
+    reader: R,
+    peer: SocketAddr,
+    hosts: Arc<VirtualHosts<S>>,
+    config: ConnectionConfig,
+    unanswered: Arc<AtomicUsize>,
+    mut shutdown: watch::Receiver<bool>,
+    sender: UnboundedSender<Pending>,
+) where
+    R: AsyncRead + Unpin,
+    S: Clone + Send + Sync + 'static,
+{
+    let mut requests = RequestReader::new(reader).with_request_timeout(config.request_timeout);
+    let capacity = config.max_pipelined.max(1);
+    let in_flight = Arc::new(Semaphore::new(capacity));
+
+    for served in 1..=config.max_requests {
+        let Ok(permit) = Arc::clone(&in_flight).acquire_owned().await else {
+            return;
+        };
+
+        // the idle timeout only applies until the next request starts arriving,
+        // the reader times out requests that stall after that
+        loop {
+            tokio::select! {
+                result = timeout(config.idle_timeout, requests.wait()) => match result {
+                    Ok(_) => break,
+                    // not idle while earlier responses are pending, waiting resumes where it stopped
+                    Err(_) if in_flight.available_permits() + 1 < capacity => {}
+                    Err(_) => return,
+                },
+                // stop waiting for the next request, but finish one that started arriving
+                Ok(_) = shutdown.wait_for(|stop| *stop) => {
+                    if requests.is_idle() {
+                        return;
+                    }
+                    break;
+                }
+            }
+        }
+        let result = requests.read_request().await;
+
+        let request = match result {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(e) => {
+                println!("invalid request: {:#}", e);
+                let response = match e.downcast::<HttpError>() {
+                    Ok(error) => error.into_response(),
+                    Err(_) => HttpError::bad_request("invalid request").into_response(),
+                };
+                unanswered.fetch_add(1, Ordering::SeqCst);
+                let _ = sender.send(Pending {
+                    response: Box::pin(async move { response }),
+                    version: Version::Http1_1,
+                    keep_alive: false,
+                    _permit: permit,
+                });
+                return;
+            }
+        };
+        let version = request.start_line.version;
+        let keep_alive =
+            request.keep_alive() && served < config.max_requests && !*shutdown.borrow();
+        let hosts = Arc::clone(&hosts);
+        let response: BoxFuture<'static, Response> = if config.concurrent {
+            let handler = tokio::spawn(async move { hosts.execute_from(request, peer).await });
+            Box::pin(async move {
+                handler
+                    .await
+                    .unwrap_or_else(|_| Status::ServerError(ServerError::Internal).into_response())
+            })
+        } else {
+            // runs when the writer gets to it
+            Box::pin(async move { hosts.execute_from(request, peer).await })
+        };
+
+        let pending = Pending {
+            response,
+            version,
+            keep_alive,
+            _permit: permit,
+        };
+        unanswered.fetch_add(1, Ordering::SeqCst);
+        if sender.send(pending).is_err() || !keep_alive {
+            return;
+        }
+    }
+}
+
+async fn write_responses<W: AsyncWrite + Unpin>(
+    mut writer: W,
+    mut receiver: UnboundedReceiver<Pending>,
+    unanswered: Arc<AtomicUsize>,
+    shutdown: watch::Receiver<bool>,
+) {
+    while let Some(pending) = receiver.recv().await {
+        let mut response = pending.response.await;
+        // handlers may close the connection as well
+        let keep_alive = pending.keep_alive
+            && !response
+                .header("Connection")
+                .is_some_and(|v| v.eq_ignore_ascii_case("close"));
+        let keep_alive = keep_alive && !*shutdown.borrow();
+
+        match (keep_alive, pending.version) {
+            (false, _) => {
+                response
+                    .headers
+                    .insert("Connection".to_string(), "close".to_string());
+            }
+            // persistent connections are the exception for HTTP/1.0, so they are confirmed
+            (true, Version::Http1_0) => {
+                response
+                    .headers
+                    .insert("Connection".to_string(), "keep-alive".to_string());
+            }
+            (true, Version::Http1_1) => {}
+        }
+
+        if let Err(e) = write_response(&mut writer, response).await {
+            println!("could not send response: {:#}", e);
+            return;
+        }
+        unanswered.fetch_sub(1, Ordering::SeqCst);
+        if !keep_alive {
+            return;
+        }
+    }
+}
+
+// reads the requests sent on a connection one after another
+// bytes read past the end of a request are kept as the start of the next one
+pub struct RequestReader<R> {
+    reader: R,
+    buffer: Vec<u8>,
+    request_timeout: Option<Duration>,
+}
+
+impl<R: AsyncRead + Unpin> RequestReader<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            buffer: Vec::new(),
+            request_timeout: None,
+        }
+    }
+
+    // how long a request that started arriving may go without further bytes
+    pub fn with_request_timeout(mut self, request_timeout: Duration) -> Self {
+        self.request_timeout = Some(request_timeout);
+        self
+    }
+
+    // whether no part of a further request was received yet
+    pub fn is_idle(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    // waits until part of the next request was received or the client closed the connection
+    // cancel safe, nothing received is lost
+    pub async fn wait(&mut self) -> Result<()> {
+        if self.buffer.is_empty() {
+            self.fill().await?;
+        }
+        Ok(())
+    }
+
+    // the next request, None if the client closed the connection between requests
+    // bodies are delimited by Content-Length, transfer encodings are not supported
+    // cancel safe, a request read partially is completed by the next call
+    pub async fn read_request(&mut self) -> Result<Option<Request>> {
+        let head_end = loop {
+            if let Some(pos) = self.buffer.windows(4).position(|w| w == b"\r\n\r\n") {
+                break pos + 4;
+            }
+            if self.buffer.len() > MAX_HEAD_SIZE {
+                bail!("request head is too large");
+            }
+
+            if self.fill().await? == 0 {
+                if self.buffer.is_empty() {
+                    return Ok(None);
+                }
+                bail!("connection closed in the middle of a request");
+            }
+        };
+
+        let head = std::str::from_utf8(&self.buffer[..head_end])?;
+        if header_value(head, "Transfer-Encoding").is_some() {
+            bail!("transfer encodings are not supported");
+        }
+        let announced = content_length(head)?;
+        let length = announced.unwrap_or(0);
+        let end = match head_end.checked_add(length) {
+            Some(end) if length <= MAX_BODY_SIZE => end,
+            _ => {
+                return Err(HttpError::new(
+                    Status::ClientError(ClientError::ContentTooLarge),
+                    "request body is too large",
+                )
+                .into())
+            }
+        };
+
+        while self.buffer.len() < end {
+            if self.fill().await? == 0 {
+                bail!("connection closed before the body was received");
+            }
+        }
+
+        let rest = self.buffer.split_off(end);
+        let mut message = std::mem::replace(&mut self.buffer, rest);
+        // the body is framed by the length checked above, not parsed from the headers again
+        let body = message.split_off(head_end);
+        let mut request = Request::parse_head(std::str::from_utf8(&message[..head_end - 4])?)?;
+        if announced.is_some() {
+            request.body = Some(String::from_utf8(body)?);
+        }
+        Ok(Some(request))
+    }
+
+    // the request timeout only applies once a request started arriving
+    async fn fill(&mut self) -> Result<usize> {
+        let mut chunk = [0; 1024];
+        let read = self.reader.read(&mut chunk);
+        let bytes_read = match self.request_timeout {
+            Some(limit) if !self.buffer.is_empty() => match timeout(limit, read).await {
+                Ok(result) => result?,
+                Err(_) => {
+                    return Err(HttpError::new(
+                        Status::ClientError(ClientError::RequestTimeout),
+                        "request was not received in time",
+                    )
+                    .into())
+                }
+            },
+            _ => read.await?,
+        };
+        self.buffer.extend_from_slice(&chunk[..bytes_read]);
+        Ok(bytes_read)
+    }
+}
+
+// sends response, framed by Content-Length so the client knows where the next response starts
+pub async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut response: Response,
+) -> Result<()> {
+    let no_content = response.start_line.status() == Status::Successful(Successful::NoContent);
+    if !no_content && response.header("Content-Length").is_none() {
+        let length = response.body.as_ref().map_or(0, |body| body.len());
+        response
+            .headers
+            .insert("Content-Length".to_string(), length.to_string());
+    }
+
+    let raw: String = response.into();
+    writer.write_all(raw.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt, DuplexStream},
+        sync::watch,
+        task::JoinHandle,
+    };
+
+    use crate::http::{
+        message::{
+            request::Method,
+            response::{HttpError, Response},
+        },
+        router::{extract::State, Params, Router, VirtualHosts},
+    };
+
+    use super::{serve, write_response, ConnectionConfig, RequestReader, MAX_BODY_SIZE};
+
+    // (handlers running, most handlers seen running at once)
+    type Running = Arc<(AtomicUsize, AtomicUsize)>;
+
+    // answers /{ms} after sleeping for ms
+    async fn sleep(params: Params, State(running): State<Running>) -> Response {
+        let ms: u64 = params.get("ms").unwrap().parse().unwrap();
+        let now = running.0.fetch_add(1, Ordering::SeqCst) + 1;
+        running.1.fetch_max(now, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(ms)).await;
+        running.0.fetch_sub(1, Ordering::SeqCst);
+
+        Response::ok(HashMap::new(), Some(ms.to_string()))
+    }
+
+    // a connection to a server answering /{ms} with sleep
+    fn connect(
+        running: &Running,
+        config: ConnectionConfig,
+        shutdown: watch::Receiver<bool>,
+    ) -> (DuplexStream, JoinHandle<()>) {
+        let mut router = Router::new(sleep, Arc::clone(running));
+        router.add(Method::Get, "/{ms}".to_string(), sleep).unwrap();
+        let mut hosts = VirtualHosts::new();
+        hosts.fallback(router);
+
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (reader, writer) = tokio::io::split(server);
+        let peer = "127.0.0.1:4221".parse().unwrap();
+        let serving = tokio::spawn(serve(
+            reader,
+            writer,
+            peer,
+            Arc::new(hosts),
+            config,
+            shutdown,
+        ));
+        (client, serving)
+    }
+
+    // pipelines requests for the given sleeps and returns the bodies in the order received
+    async fn pipeline(sleeps: &[u64], config: ConnectionConfig) -> (Vec<String>, usize) {
+        let running = Running::default();
+        let (_shutdown, stopping) = watch::channel(false);
+        let (client, serving) = connect(&running, config, stopping);
+
+        let (mut client_reader, mut client_writer) = tokio::io::split(client);
+        let mut raw = String::new();
+        for (i, ms) in sleeps.iter().enumerate() {
+            let close = if i + 1 == sleeps.len() {
+                "Connection: close\r\n"
+            } else {
+                ""
+            };
+            raw.push_str(&format!("GET /{} HTTP/1.1\r\nHost: x\r\n{}\r\n", ms, close));
+        }
+        client_writer.write_all(raw.as_bytes()).await.unwrap();
+
+        let mut received = String::new();
+        client_reader.read_to_string(&mut received).await.unwrap();
+        serving.await.unwrap();
+
+        let bodies = received
+            .split("HTTP/1.1 200 OK")
+            .skip(1)
+            .map(|response| response.rsplit("\r\n").next().unwrap().to_string())
+            .collect();
+        (bodies, running.1.load(Ordering::SeqCst))
+    }
+
+    #[tokio::test]
+    async fn pipelining() {
+        // slow requests first, responses in request order anyway
+        let sleeps = [40, 30, 20, 10];
+        let expected = vec!["40", "30", "20", "10"];
+
+        let (bodies, most) = pipeline(&sleeps, ConnectionConfig::default()).await;
+        assert_eq!(bodies, expected);
+        assert_eq!(most, 1);
+
+        let concurrent = ConnectionConfig {
+            concurrent: true,
+            max_pipelined: 2,
+            ..ConnectionConfig::default()
+        };
+        let (bodies, most) = pipeline(&sleeps, concurrent).await;
+        assert_eq!(bodies, expected);
+        assert_eq!(most, 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let running = Running::default();
+        let (shutdown, stopping) = watch::channel(false);
+        let (mut client, serving) = connect(&running, ConnectionConfig::default(), stopping);
+
+        client
+            .write_all(b"GET /30 HTTP/1.1\r\nHost: x\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        shutdown.send(true).unwrap();
+
+        // the request in flight is answered, then the connection is closed
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        serving.await.unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+        assert!(received.contains("Connection: close"));
+        assert!(received.ends_with("30"));
+    }
+
+    #[tokio::test]
+    async fn timeouts() {
+        let running = Running::default();
+        let (_shutdown, stopping) = watch::channel(false);
+        let config = ConnectionConfig {
+            idle_timeout: Duration::from_millis(200),
+            request_timeout: Duration::from_millis(100),
+            ..ConnectionConfig::default()
+        };
+
+        // a slow upload outlasting the idle timeout is fine as long as bytes keep arriving
+        let (mut client, serving) = connect(&running, config, stopping.clone());
+        client
+            .write_all(
+                b"GET /0 HTTP/1.1\r\nHost: x\r\nConnection: close\r\nContent-Length: 10\r\n\r\n",
+            )
+            .await
+            .unwrap();
+        for _ in 0..10 {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            client.write_all(b"x").await.unwrap();
+        }
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        serving.await.unwrap();
+        assert!(received.starts_with("HTTP/1.1 200 OK"));
+
+        // a request that stalls is answered with 408
+        let (mut client, serving) = connect(&running, config, stopping);
+        client
+            .write_all(b"GET /0 HTTP/1.1\r\nHost: x\r\nContent-Length: 10\r\n\r\nxx")
+            .await
+            .unwrap();
+        let mut received = String::new();
+        client.read_to_string(&mut received).await.unwrap();
+        serving.await.unwrap();
+        assert!(received.starts_with("HTTP/1.1 408 Request Timeout"));
+    }
+
+    #[tokio::test]
+    async fn read_requests() {
+        // two requests arriving in one read
+        let raw: &[u8] =
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /b HTTP/1.1\r\nHost: x\r\n\r\n";
+        let mut reader = RequestReader::new(raw);
+
+        let first = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(first.start_line.method, Method::Post);
+        assert_eq!(first.body.as_deref(), Some("hello"));
+
+        let second = reader.read_request().await.unwrap().unwrap();
+        assert_eq!(second.start_line.target, "/b");
+        assert_eq!(second.body, None);
+
+        assert!(reader.read_request().await.unwrap().is_none());
+
+        let truncated: &[u8] = b"POST /a HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel";
+        assert!(RequestReader::new(truncated).read_request().await.is_err());
+        let chunked: &[u8] = b"POST /a HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        assert!(RequestReader::new(chunked).read_request().await.is_err());
+        let conflicting: &[u8] =
+            b"POST /a HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 3\r\n\r\nhello";
+        assert!(RequestReader::new(conflicting)
+            .read_request()
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn body_size() {
+        let status = |raw: String| async move {
+            let raw = raw.into_bytes();
+            let error = RequestReader::new(raw.as_slice())
+                .read_request()
+                .await
+                .err()
+                .unwrap();
+            let status: u16 = error.downcast::<HttpError>().unwrap().status().into();
+            status
+        };
+
+        // would overflow when added to the length of the head
+        let overflow = format!("POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n", usize::MAX);
+        assert_eq!(status(overflow).await, 413);
+        let large = format!(
+            "POST /a HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY_SIZE + 1
+        );
+        assert_eq!(status(large).await, 413);
+    }
+
+    #[tokio::test]
+    async fn content_length() {
+        let mut written = Vec::new();
+        write_response(
+            &mut written,
+            Response::ok(HashMap::new(), Some("hi".to_string())),
+        )
+        .await
+        .unwrap();
+        assert_eq!(written, b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nhi");
+
+        let mut written = Vec::new();
+        write_response(&mut written, Response::no_content(HashMap::new()))
+            .await
+            .unwrap();
+        assert_eq!(written, b"HTTP/1.1 204 No Content\r\n\r\n");
+    }
+}
@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod message;
 pub mod request;
 pub mod response;
@@ -2,28 +2,27 @@ use std::collections::HashMap;
 
 use anyhow::{bail, Context, Result};
 
-use super::{
-    request::{Request, RequestLine},
-    response::{ClientError, ServerError, Status, StatusLine, Successful},
-};
+use super::response::{ClientError, ServerError, Status, StatusLine, Successful};
 
 pub trait Startline {}
 
 #[derive(Clone)]
-pub(crate) struct HttpMessage<T: Startline> {
+pub struct HttpMessage<T: Startline> {
     pub start_line: T,
     pub headers: HashMap<String, String>,
     pub body: Option<String>,
 }
 
-#[derive(PartialEq, Eq, Debug)]
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
 pub enum Version {
+    Http1_0,
     Http1_1,
 }
 
-impl Into<String> for Version {
-    fn into(self) -> String {
-        match self {
+impl From<Version> for String {
+    fn from(value: Version) -> Self {
+        match value {
+            Version::Http1_0 => String::from("HTTP/1.0"),
             Version::Http1_1 => String::from("HTTP/1.1"),
         }
     }
@@ -34,6 +33,7 @@ impl TryFrom<String> for Version {
 
     fn try_from(value: String) -> Result<Self, Self::Error> {
         match value.as_str() {
+            "HTTP/1.0" => Ok(Version::Http1_0),
             "HTTP/1.1" => Ok(Version::Http1_1),
             _ => bail!("unknown version"),
         }
@@ -52,11 +52,19 @@ impl<T: Startline> HttpMessage<T> {
     pub fn write(&mut self, body: String) {
         self.body = Some(body);
     }
+
+    // header names are case-insensitive, the stored map keeps them as received
+    pub fn header(&self, name: &str) -> Option<&String> {
+        self.headers
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
 }
 
-impl<T: Startline + Into<String>> Into<String> for HttpMessage<T> {
-    fn into(self) -> String {
-        let mut header_string = self
+impl<T: Startline + Into<String>> From<HttpMessage<T>> for String {
+    fn from(value: HttpMessage<T>) -> Self {
+        let mut header_string = value
             .headers
             .iter()
             .map(|(k, v)| format!("{}: {}", k, v))
@@ -69,9 +77,9 @@ impl<T: Startline + Into<String>> Into<String> for HttpMessage<T> {
 
         format!(
             "{}\r\n{}\r\n{}",
-            self.start_line.into(),
+            value.start_line.into(),
             header_string,
-            self.body.unwrap_or(String::new())
+            value.body.unwrap_or_default()
         )
     }
 }
@@ -93,6 +101,17 @@ impl HttpMessage<StatusLine> {
         }
     }
 
+    pub fn no_content(headers: HashMap<String, String>) -> Self {
+        HttpMessage::<StatusLine> {
+            headers,
+            start_line: StatusLine::new(
+                Version::Http1_1,
+                Status::Successful(Successful::NoContent),
+            ),
+            body: None,
+        }
+    }
+
     pub fn not_found() -> Self {
         HttpMessage::<StatusLine> {
             headers: HashMap::new(),
@@ -103,6 +122,18 @@ impl HttpMessage<StatusLine> {
             body: None,
         }
     }
+
+    pub fn method_not_allowed(headers: HashMap<String, String>) -> Self {
+        HttpMessage::<StatusLine> {
+            headers,
+            start_line: StatusLine::new(
+                Version::Http1_1,
+                Status::ClientError(ClientError::MethodNotAllowed),
+            ),
+            body: None,
+        }
+    }
+
     pub fn internal_error() -> Self {
         HttpMessage::<StatusLine> {
             headers: HashMap::new(),
@@ -115,7 +146,28 @@ impl HttpMessage<StatusLine> {
     }
 }
 
-impl TryFrom<String> for HttpMessage<RequestLine> {
+// requests and responses share the same layout, only the start line differs
+impl<T> HttpMessage<T>
+where
+    T: Startline + TryFrom<String, Error = anyhow::Error>,
+{
+    // the start line and headers of a message, without the blank line ending them
+    pub(crate) fn parse_head(head: &str) -> Result<Self> {
+        let (raw_start_line, header_section) = head.split_once("\r\n").unwrap_or((head, ""));
+        let start_line: T = raw_start_line.to_string().try_into()?;
+
+        let mut message = HttpMessage::new(start_line, HashMap::new(), None);
+        if !header_section.is_empty() {
+            message.headers = parse_headers(header_section)?;
+        }
+        Ok(message)
+    }
+}
+
+impl<T> TryFrom<String> for HttpMessage<T>
+where
+    T: Startline + TryFrom<String, Error = anyhow::Error>,
+{
     type Error = anyhow::Error;
 
     fn try_from(value: String) -> anyhow::Result<Self, Self::Error> {
@@ -123,30 +175,50 @@ impl TryFrom<String> for HttpMessage<RequestLine> {
         // [Headers] \r\n
         // [BODY]
 
-        let (raw_request_line, remaining) = value
-            .split_once("\r\n")
-            .context("could not read request_line")?;
-
-        let request_line: RequestLine = raw_request_line.to_string().try_into()?;
-
-        // get headers (and possible body) section
-        let mut headers: HashMap<String, String> = HashMap::new();
-        let mut body: Option<String> = None;
-        if &remaining[..4] != "\r\n" {
-            let (header_section, remaining) = remaining
-                .split_once("\r\n\r\n")
-                .context("could not read headers")?;
-            headers = parse_headers(header_section)?;
-
-            // normally we would need to deal with the content type as well, but for now let's just stick with the length
-            if let Some(content_length) = headers.get("Content-Length") {
-                let len = content_length.parse::<usize>()?;
-                body = Some(remaining[..len].to_string());
-            }
+        let (head, remaining) = value
+            .split_once("\r\n\r\n")
+            .context("could not read headers")?;
+        let mut message = Self::parse_head(head)?;
+
+        // normally we would need to deal with the content type as well, but for now let's just stick with the length
+        if let Some(len) = content_length(head)? {
+            let body = remaining
+                .get(..len)
+                .context("body is shorter than Content-Length")?;
+            message.body = Some(body.to_string());
         }
 
-        Ok(Request::new(request_line, headers, body))
+        Ok(message)
+    }
+}
+
+// the body length announced by a raw message head, None without Content-Length
+// repeated Content-Length headers have to agree (RFC 9112, section 6.3)
+pub(crate) fn content_length(head: &str) -> Result<Option<usize>> {
+    let mut length = None;
+    let values = raw_headers(head).filter(|(k, _)| k.eq_ignore_ascii_case("Content-Length"));
+    for (_, value) in values {
+        let value = value.parse::<usize>()?;
+        if length.is_some_and(|length| length != value) {
+            bail!("conflicting Content-Length headers");
+        }
+        length = Some(value);
     }
+    Ok(length)
+}
+
+// a header of a raw message head (start line and headers), before it is parsed
+pub(crate) fn header_value<'a>(head: &'a str, name: &str) -> Option<&'a str> {
+    raw_headers(head)
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v)
+}
+
+fn raw_headers(head: &str) -> impl Iterator<Item = (&str, &str)> {
+    head.split("\r\n")
+        .skip(1)
+        .filter_map(|line| line.split_once(':'))
+        .map(|(k, v)| (k.trim(), v.trim()))
 }
 
 fn parse_headers(raw: &str) -> anyhow::Result<HashMap<String, String>> {
@@ -168,7 +240,7 @@ mod tests {
     use std::collections::HashMap;
 
     use crate::http::message::{
-        message::{parse_headers, Version},
+        message::{content_length, parse_headers, Version},
         request::{Method, Request},
         response::{Status, StatusLine, Successful},
     };
@@ -215,6 +287,19 @@ mod tests {
         );
     }
 
+    #[test]
+    fn conflicting_content_length() {
+        let agreeing = "POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 2\r\n\r\nhi";
+        let message: Request = TryFrom::<String>::try_from(agreeing.to_string()).unwrap();
+        assert_eq!(message.body.as_deref(), Some("hi"));
+
+        let conflicting = "POST / HTTP/1.1\r\nContent-Length: 2\r\ncontent-length: 1\r\n\r\nhi";
+        assert!(content_length(conflicting).is_err());
+        assert!(TryFrom::<String>::try_from(conflicting.to_string())
+            .map(|_: Request| ())
+            .is_err());
+    }
+
     #[test]
     fn headers() {
         const HEADER: &str = "Header: Value\r\nFoo: Bar";
@@ -3,17 +3,32 @@ use anyhow::{bail, Context, Result};
 
 pub type Request = HttpMessage<RequestLine>;
 
-#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+#[derive(PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Clone, Copy)]
 pub enum Method {
     Get,
     Post,
+    Put,
+    Patch,
+    Delete,
+    Options,
 }
 
-impl Into<String> for Method {
-    fn into(self) -> String {
-        match self {
+impl Method {
+    // whether sending the request twice has the same effect as sending it once (RFC 9110, section 9.2.2)
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(self, Method::Post | Method::Patch)
+    }
+}
+
+impl From<Method> for String {
+    fn from(value: Method) -> Self {
+        match value {
             Method::Get => "GET".to_string(),
             Method::Post => "POST".to_string(),
+            Method::Put => "PUT".to_string(),
+            Method::Patch => "PATCH".to_string(),
+            Method::Delete => "DELETE".to_string(),
+            Method::Options => "OPTIONS".to_string(),
         }
     }
 }
@@ -25,20 +40,59 @@ impl TryFrom<String> for Method {
         match value.as_str() {
             "GET" => Ok(Method::Get),
             "POST" => Ok(Method::Post),
+            "PUT" => Ok(Method::Put),
+            "PATCH" => Ok(Method::Patch),
+            "DELETE" => Ok(Method::Delete),
+            "OPTIONS" => Ok(Method::Options),
             _ => bail!("invalid input"),
         }
     }
 }
 
-#[allow(dead_code)]
+#[derive(Clone)]
 pub struct RequestLine {
     pub method: Method,
     pub target: String,
     pub version: Version,
 }
 
+impl RequestLine {
+    pub fn new(method: Method, target: String, version: Version) -> Self {
+        Self {
+            method,
+            target,
+            version,
+        }
+    }
+}
+
 impl Startline for RequestLine {}
 
+impl Request {
+    // whether the client wants to send further requests on the connection
+    // HTTP/1.1 connections persist unless closed, HTTP/1.0 ones only if asked for (RFC 9112, section 9.3)
+    pub fn keep_alive(&self) -> bool {
+        let has = |option: &str| {
+            self.header("Connection")
+                .is_some_and(|v| v.split(',').any(|o| o.trim().eq_ignore_ascii_case(option)))
+        };
+
+        match self.start_line.version {
+            Version::Http1_0 => has("keep-alive"),
+            Version::Http1_1 => !has("close"),
+        }
+    }
+}
+
+impl From<RequestLine> for String {
+    fn from(value: RequestLine) -> Self {
+        let method: String = value.method.into();
+        let version: String = value.version.into();
+
+        format!("{} {} {}", method, value.target, version)
+    }
+}
+
 impl TryFrom<String> for RequestLine {
     type Error = anyhow::Error;
 
@@ -63,20 +117,25 @@ fn parse_requestline(raw: &str) -> Result<RequestLine> {
         .to_string()
         .try_into()?;
 
-    Ok(RequestLine {
-        method,
-        target: target.to_string(),
-        version,
-    })
+    Ok(RequestLine::new(method, target.to_string(), version))
 }
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
+
     use crate::http::message::{
         message::Version,
-        request::{parse_requestline, Method},
+        request::{parse_requestline, Method, Request, RequestLine},
     };
 
+    #[test]
+    fn serialize_request_line() {
+        let request_line = RequestLine::new(Method::Post, "/files/a".to_string(), Version::Http1_1);
+
+        assert_eq!(Into::<String>::into(request_line), "POST /files/a HTTP/1.1");
+    }
+
     #[test]
     fn request_line() {
         let request_line = parse_requestline("GET / HTTP/1.1").unwrap();
@@ -85,4 +144,23 @@ mod tests {
         assert_eq!(request_line.target, "/");
         assert_eq!(request_line.version, Version::Http1_1);
     }
+
+    #[test]
+    fn keep_alive() {
+        let request = |version, connection: Option<&str>| {
+            let headers = connection
+                .map(|v| HashMap::from([("connection".to_string(), v.to_string())]))
+                .unwrap_or_default();
+            Request::new(
+                RequestLine::new(Method::Get, "/".to_string(), version),
+                headers,
+                None,
+            )
+        };
+
+        assert!(request(Version::Http1_1, None).keep_alive());
+        assert!(!request(Version::Http1_1, Some("Upgrade, Close")).keep_alive());
+        assert!(!request(Version::Http1_0, None).keep_alive());
+        assert!(request(Version::Http1_0, Some("Keep-Alive")).keep_alive());
+    }
 }
@@ -1,42 +1,96 @@
+use std::{collections::HashMap, fmt};
+
 use super::message::{HttpMessage, Startline, Version};
+use anyhow::{bail, Context, Result};
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 pub type Response = HttpMessage<StatusLine>;
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Eq, PartialEq, Clone, Copy)]
 pub enum Status {
     Successful(Successful),
+    Redirection(Redirection),
     ClientError(ClientError),
     ServerError(ServerError),
+    // a code without a variant of its own, e.g. received by the client
+    Other(u16),
 }
 
-impl Into<u16> for Status {
+impl From<Status> for u16 {
     // TODO: when turning this into a library we
     // have to implement try_into (to ensure we don't crash if we forget codes?)
     // have to implement checked_add (in order to ensure we don't overflow)
     // do we really though? this is all internally controlled code.. ?
 
-    fn into(self) -> u16 {
-        match self {
+    fn from(value: Status) -> Self {
+        match value {
             Status::Successful(s) => 200 + Into::<u8>::into(s) as u16,
+            Status::Redirection(r) => 300 + Into::<u8>::into(r) as u16,
             Status::ClientError(c) => 400 + Into::<u8>::into(c) as u16,
             Status::ServerError(s) => 500 + Into::<u8>::into(s) as u16,
+            Status::Other(code) => code,
         }
     }
 }
 
-impl Into<String> for Status {
-    fn into(self) -> String {
-        match self {
+// known codes get their variant, any other three-digit code is kept as Other
+impl TryFrom<u16> for Status {
+    type Error = anyhow::Error;
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        let known = match value {
+            200..=299 => Successful::try_from((value - 200) as u8)
+                .ok()
+                .map(Status::Successful),
+            300..=399 => Redirection::try_from((value - 300) as u8)
+                .ok()
+                .map(Status::Redirection),
+            400..=499 => ClientError::try_from((value - 400) as u8)
+                .ok()
+                .map(Status::ClientError),
+            500..=599 => ServerError::try_from((value - 500) as u8)
+                .ok()
+                .map(Status::ServerError),
+            _ => None,
+        };
+
+        match known {
+            Some(status) => Ok(status),
+            None if (100..=999).contains(&value) => Ok(Status::Other(value)),
+            None => bail!("invalid status code {}", value),
+        }
+    }
+}
+
+impl From<Status> for String {
+    fn from(value: Status) -> Self {
+        match value {
             Status::Successful(s) => match s {
                 Successful::Ok => "OK".to_string(),
                 Successful::Created => "Created".to_string(),
+                Successful::NoContent => "No Content".to_string(),
+            },
+            Status::Redirection(r) => match r {
+                Redirection::MovedPermanently => "Moved Permanently".to_string(),
+                Redirection::Found => "Found".to_string(),
+                Redirection::SeeOther => "See Other".to_string(),
+                Redirection::TemporaryRedirect => "Temporary Redirect".to_string(),
+                Redirection::PermanentRedirect => "Permanent Redirect".to_string(),
             },
             Status::ClientError(c) => match c {
+                ClientError::BadRequest => "Bad Request".to_string(),
                 ClientError::NotFound => "Not Found".to_string(),
+                ClientError::MethodNotAllowed => "Method Not Allowed".to_string(),
+                ClientError::RequestTimeout => "Request Timeout".to_string(),
+                ClientError::ContentTooLarge => "Content Too Large".to_string(),
+                ClientError::UnsupportedMediaType => "Unsupported Media Type".to_string(),
+                ClientError::MisdirectedRequest => "Misdirected Request".to_string(),
             },
             Status::ServerError(s) => match s {
                 ServerError::Internal => "Internal Server Error".to_string(),
+                ServerError::LoopDetected => "Loop Detected".to_string(),
             },
+            // the reason phrase may be empty (RFC 9112, section 4)
+            Status::Other(_) => String::new(),
         }
     }
 }
@@ -46,20 +100,39 @@ impl Into<String> for Status {
 pub enum Successful {
     Ok = 0,
     Created = 1,
+    NoContent = 4,
+}
+
+#[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Clone, Copy)]
+#[repr(u8)]
+pub enum Redirection {
+    MovedPermanently = 1,
+    Found = 2,
+    SeeOther = 3,
+    TemporaryRedirect = 7,
+    PermanentRedirect = 8,
 }
 
 #[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Clone, Copy)]
 #[repr(u8)]
 pub enum ServerError {
     Internal = 0,
+    LoopDetected = 8,
 }
 
 #[derive(Debug, Eq, PartialEq, IntoPrimitive, TryFromPrimitive, Clone, Copy)]
 #[repr(u8)]
 pub enum ClientError {
+    BadRequest = 0,
     NotFound = 4,
+    MethodNotAllowed = 5,
+    RequestTimeout = 8,
+    ContentTooLarge = 13,
+    UnsupportedMediaType = 15,
+    MisdirectedRequest = 21,
 }
 
+#[derive(Clone)]
 pub struct StatusLine {
     version: Version,
     status: Status,
@@ -69,16 +142,242 @@ impl StatusLine {
     pub fn new(version: Version, status: Status) -> Self {
         Self { version, status }
     }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
 }
 
 impl Startline for StatusLine {}
 
-impl Into<String> for StatusLine {
-    fn into(self) -> String {
-        let code: u16 = self.status.into();
-        let message: String = self.status.into();
-        let version: String = self.version.into();
+impl From<StatusLine> for String {
+    fn from(value: StatusLine) -> Self {
+        let code: u16 = value.status.into();
+        let message: String = value.status.into();
+        let version: String = value.version.into();
 
         format!("{} {} {}", version, code, message)
     }
 }
+
+impl TryFrom<String> for StatusLine {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        parse_statusline(value.as_str())
+    }
+}
+
+fn parse_statusline(raw: &str) -> Result<StatusLine> {
+    // the reason phrase may contain spaces, we derive our own from the code anyway
+    let mut split = raw.splitn(3, ' ');
+    let version: Version = split
+        .next()
+        .context("could not read version")?
+        .to_string()
+        .try_into()?;
+    let status: Status = split
+        .next()
+        .context("could not read status code")?
+        .parse::<u16>()?
+        .try_into()?;
+
+    Ok(StatusLine::new(version, status))
+}
+
+// anything a handler can return
+pub trait IntoResponse {
+    fn into_response(self) -> Response;
+}
+
+impl IntoResponse for Response {
+    fn into_response(self) -> Response {
+        self
+    }
+}
+
+// an empty response with the given status
+impl IntoResponse for Status {
+    fn into_response(self) -> Response {
+        HttpMessage::new(
+            StatusLine::new(Version::Http1_1, self),
+            HashMap::new(),
+            None,
+        )
+    }
+}
+
+// a 200 with a text/plain body
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        text(Status::Successful(Successful::Ok), self)
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        self.to_string().into_response()
+    }
+}
+
+// a text/plain body with the given status
+impl<B: Into<String>> IntoResponse for (Status, B) {
+    fn into_response(self) -> Response {
+        text(self.0, self.1.into())
+    }
+}
+
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self) -> Response {
+        match self {
+            Ok(value) => value.into_response(),
+            Err(error) => error.into_response(),
+        }
+    }
+}
+
+// an HttpError anywhere in the chain keeps its status,
+// any other error is logged and answered with a bare 500, its message is not leaked to the client
+impl IntoResponse for anyhow::Error {
+    fn into_response(self) -> Response {
+        match self.downcast::<HttpError>() {
+            Ok(error) => error.into_response(),
+            Err(error) => {
+                eprintln!("handler failed: {:#}", error);
+                Response::internal_error()
+            }
+        }
+    }
+}
+
+// an error to be answered with a specific status, the message is sent as the body
+#[derive(Debug, Clone, PartialEq)]
+pub struct HttpError {
+    status: Status,
+    message: String,
+}
+
+impl HttpError {
+    pub fn new(status: Status, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        HttpError::new(Status::ClientError(ClientError::BadRequest), message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        HttpError::new(Status::ClientError(ClientError::NotFound), message)
+    }
+
+    pub fn status(&self) -> Status {
+        self.status
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let code: u16 = self.status.into();
+        write!(f, "{}: {}", code, self.message)
+    }
+}
+
+impl std::error::Error for HttpError {}
+
+impl IntoResponse for HttpError {
+    fn into_response(self) -> Response {
+        text(self.status, self.message)
+    }
+}
+
+fn text(status: Status, body: String) -> Response {
+    let headers = HashMap::from([
+        ("Content-Type".to_string(), "text/plain".to_string()),
+        ("Content-Length".to_string(), body.len().to_string()),
+    ]);
+
+    HttpMessage::new(
+        StatusLine::new(Version::Http1_1, status),
+        headers,
+        Some(body),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context;
+
+    use crate::http::message::{
+        message::Version,
+        response::{
+            parse_statusline, ClientError, HttpError, IntoResponse, Redirection, Response,
+            ServerError, Status,
+        },
+    };
+
+    #[test]
+    fn status_line() {
+        let status_line = parse_statusline("HTTP/1.1 404 Not Found").unwrap();
+
+        assert_eq!(status_line.version, Version::Http1_1);
+        assert_eq!(
+            status_line.status,
+            Status::ClientError(ClientError::NotFound)
+        );
+    }
+
+    #[test]
+    fn unknown_status_line() {
+        let status_line = parse_statusline("HTTP/1.1 503 Service Unavailable").unwrap();
+        assert_eq!(status_line.status, Status::Other(503));
+        assert_eq!(Into::<String>::into(status_line), "HTTP/1.1 503 ");
+    }
+
+    #[test]
+    fn status_codes() {
+        assert_eq!(
+            Status::try_from(308).unwrap(),
+            Status::Redirection(Redirection::PermanentRedirect)
+        );
+        assert_eq!(
+            Into::<u16>::into(Status::Redirection(Redirection::Found)),
+            302
+        );
+        assert_eq!(Status::try_from(503).unwrap(), Status::Other(503));
+        assert_eq!(Into::<u16>::into(Status::Other(503)), 503);
+        assert!(Status::try_from(99).is_err());
+        assert!(Status::try_from(1000).is_err());
+    }
+
+    #[test]
+    fn into_response() {
+        let status = |response: Response| Into::<u16>::into(response.start_line.status());
+
+        let response = "hello".into_response();
+        assert_eq!(status(response.clone()), 200);
+        assert_eq!(response.header("Content-Length").unwrap(), "5");
+
+        let response = (Status::ClientError(ClientError::NotFound), "gone").into_response();
+        assert_eq!(status(response.clone()), 404);
+        assert_eq!(response.body.unwrap(), "gone");
+
+        let result: Result<Response, HttpError> = Err(HttpError::bad_request("no"));
+        assert_eq!(status(result.into_response()), 400);
+
+        // the status survives conversion into anyhow::Error and added context
+        let error = Err::<(), _>(HttpError::bad_request("no"))
+            .context("while testing")
+            .unwrap_err();
+        assert_eq!(status(error.into_response()), 400);
+
+        let response = anyhow::anyhow!("secret").into_response();
+        assert_eq!(
+            response.start_line.status(),
+            Status::ServerError(ServerError::Internal)
+        );
+        assert_eq!(response.body, None);
+    }
+}
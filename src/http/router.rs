@@ -1,116 +1,1189 @@
-use anyhow::Result;
-use regex::Regex;
+use anyhow::{bail, Result};
+use serde_json::Value;
 use std::{
-    collections::HashMap,
-    sync::{Arc, Mutex},
+    cell::Cell, cmp::Reverse, collections::HashMap, future::Future, net::SocketAddr, pin::Pin,
 };
 
-use crate::ApiContext;
-
 use super::message::{
+    message::Version,
     request::{Method, Request},
-    response::Response,
+    response::{ClientError, IntoResponse, Redirection, Response, Status, StatusLine},
 };
 
-type Callback = Box<dyn Fn(&Request, String, &Arc<Mutex<ApiContext>>) -> Result<Response>>;
+pub mod extract;
+mod guard;
+mod handler;
+mod hosts;
+mod middleware;
+mod openapi;
+mod params;
+mod rules;
+mod tree;
+mod urls;
+
+pub use guard::{ContentType, Guard, Header, QueryFlag};
+use handler::ErasedHandler;
+pub use handler::Handler;
+pub use hosts::{parse_host_pattern, VirtualHosts};
+pub use middleware::{Middleware, Next};
+pub use openapi::{Content, Docs};
+use params::Constraint;
+pub use params::{ParamValue, Params};
+pub use rules::{Rule, Rules};
+use tree::Match;
+pub use urls::Urls;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+// handlers and middleware are Send + Sync, so a router can be shared between connections
+type Callback<S> = Box<dyn ErasedHandler<S> + Send + Sync>;
+
+// routes are matched segment by segment, without depending on registration order
+// when several routes match a path, the most specific one wins:
+// a static segment beats a {parameter} in the same position, which beats a {*catch_all}
+// {name} matches exactly one non-empty segment, {*name} matches the (non-empty) rest of the path
+// {name:constraint} only matches segments satisfying the constraint (u64, i64, uuid, alpha or a regex),
+// constrained parameters are tried before unconstrained ones
+// a path registered for other methods only is answered with 405 and an Allow header
+// routes that only differ in the names of their parameters are ambiguous and cannot be added
+//
+// routers can be nested under a static prefix, a nested router owns everything below its prefix
+// (its own default handles unknown paths there) and sees paths with the prefix stripped
+//
+// middleware runs outermost first: the layers of the router the request arrived at,
+// then those of nested routers on the way down, then the layers of the matched route.
+// within a router or route, layers run in the order they were added.
+// router layers see every request (including 404/405 answers) before routing, route layers
+// only see requests for their route
+//
+// every handler receives a clone of the router state S, how it is shared (Arc, RwLock, atomics, ...)
+// is up to the application
+//
+// routes can be named to generate their URLs (see Urls), names are unique across nested routers
+//
+// routes with guards only handle requests their guards accept, several guarded routes (and one
+// unguarded fallback) can share method and template. guarded routes are tried in registration order,
+// a refused route counts as not matching, so a less specific route may still handle the request.
+// if the path and method match but every route refuses, the rejection status is returned (404 by default)
+//
+// trailing slashes and case are matched strictly by default, see TrailingSlash and
+// Router::case_insensitive. both only apply to the router they are set on, not to nested routers
+pub struct Router<S> {
+    default: Callback<S>,
+    // the candidates for a template and method, guarded ones first
+    routes: tree::Node<Vec<Endpoint<S>>>,
+    nested: Vec<(String, Router<S>)>,
+    middleware: Vec<Box<dyn Middleware>>,
+    urls: Urls,
+    trailing_slash: TrailingSlash,
+    case_insensitive: bool,
+    rejection: Status,
+    state: S,
+}
+
+// how a path that only matches a route with(out) a trailing slash is handled
+// the form the route was registered with is always preferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrailingSlash {
+    // /user-agent/ does not match /user-agent
+    Strict,
+    // /user-agent/ is answered with a 308 to /user-agent
+    Redirect,
+    // /user-agent/ is handled by /user-agent
+    MatchBoth,
+}
 
-pub struct Router {
-    default: Callback,
-    endpoints: HashMap<String, Callback>,
-    ctx: Arc<Mutex<ApiContext>>,
+// a route to be added to a router, with its own middleware
+pub struct Route<S> {
+    method: Method,
+    template: String,
+    name: Option<String>,
+    docs: Docs,
+    callback: Callback<S>,
+    guards: Vec<Box<dyn Guard>>,
+    middleware: Vec<Box<dyn Middleware>>,
 }
 
-unsafe impl Sync for Router {}
-unsafe impl Send for Router {}
+struct Endpoint<S> {
+    name: Option<String>,
+    docs: Docs,
+    callback: Callback<S>,
+    guards: Vec<Box<dyn Guard>>,
+    middleware: Vec<Box<dyn Middleware>>,
+}
 
-const PATTERN: &str = r"\{.*?\}";
+impl<S> Endpoint<S> {
+    fn accepts(&self, request: &Request) -> bool {
+        self.guards.iter().all(|guard| guard.check(request))
+    }
+}
 
-enum RouteMatch {
-    Match(Option<String>),
-    NoMatch,
+// a registered route, as listed by Router::routes
+#[derive(Debug, Clone, PartialEq)]
+pub struct RouteInfo {
+    pub method: Method,
+    // including the prefixes of the routers it is nested in
+    pub template: String,
+    pub name: Option<String>,
+    // the middleware wrapping the route, outermost first
+    pub middleware: Vec<&'static str>,
+    pub guards: Vec<&'static str>,
+    pub docs: Docs,
 }
 
-impl Router {
-    pub fn new(default: Callback, ctx: Arc<Mutex<ApiContext>>) -> Self {
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String, Option<Constraint>),
+    CatchAll(String),
+}
+
+impl<S: Clone + Send + Sync + 'static> Router<S> {
+    pub fn new<T: 'static>(default: impl Handler<T, S>, state: S) -> Self {
         Self {
-            default,
-            endpoints: HashMap::default(),
-            ctx,
+            default: handler::erase(default),
+            routes: tree::Node::default(),
+            nested: Vec::new(),
+            middleware: Vec::new(),
+            urls: Urls::default(),
+            trailing_slash: TrailingSlash::Strict,
+            case_insensitive: false,
+            rejection: Status::ClientError(ClientError::NotFound),
+            state,
+        }
+    }
+
+    pub fn add<T: 'static>(
+        &mut self,
+        method: Method,
+        endpoint: String,
+        handler: impl Handler<T, S>,
+    ) -> Result<()> {
+        self.route(Route::new(method, endpoint, handler))
+    }
+
+    pub fn route(&mut self, route: Route<S>) -> Result<()> {
+        // the template is parsed once here instead of on every request
+        let segments = parse_template(&route.template)?;
+        if let Some(name) = &route.name {
+            if self.urls.contains(name) {
+                bail!("route name {} already in use", name);
+            }
+        }
+
+        let endpoint = Endpoint {
+            name: route.name.clone(),
+            docs: route.docs,
+            callback: route.callback,
+            guards: route.guards,
+            middleware: route.middleware,
+        };
+        match self
+            .routes
+            .get_mut(&route.template, &segments, route.method)
+        {
+            Some(candidates) => {
+                if endpoint.guards.is_empty() && candidates.iter().any(|e| e.guards.is_empty()) {
+                    let method: String = route.method.into();
+                    bail!("endpoint {} {} already registered", method, route.template);
+                }
+                candidates.push(endpoint);
+                // the unguarded fallback goes last, the order of guarded routes is kept
+                candidates.sort_by_key(|e| e.guards.is_empty());
+            }
+            None => {
+                self.routes
+                    .insert(&route.template, &segments, route.method, vec![endpoint])?;
+            }
+        }
+
+        match route.name {
+            Some(name) => self.urls.insert(&name, segments),
+            None => Ok(()),
         }
     }
 
-    pub fn add(&mut self, method: Method, endpoint: String, handler: Callback) -> Result<()> {
-        let route = Router::route_identifier(method, &endpoint);
-        if self.endpoints.contains_key(&route) {
-            anyhow::bail!("endpoint already registered");
+    // wraps every request reaching this router
+    pub fn layer(&mut self, middleware: impl Middleware + 'static) {
+        self.middleware.push(Box::new(middleware));
+    }
+
+    // the status returned when method and path match, but the guards refuse every route
+    pub fn rejection(&mut self, status: Status) {
+        self.rejection = status;
+    }
+
+    pub fn trailing_slash(&mut self, policy: TrailingSlash) {
+        self.trailing_slash = policy;
+    }
+
+    // matches static segments (and nesting prefixes) regardless of ASCII case,
+    // parameters are passed on as received
+    pub fn case_insensitive(&mut self, enabled: bool) {
+        self.case_insensitive = enabled;
+    }
+
+    // mounts router under prefix, e.g. /files/a.txt reaches router as /a.txt
+    pub fn nest(&mut self, prefix: &str, router: Router<S>) -> Result<()> {
+        let segments = parse_template(prefix)?;
+        if !segments
+            .iter()
+            .all(|s| matches!(s, Segment::Static(s) if !s.is_empty()))
+        {
+            bail!(
+                "prefix {} must consist of static, non-empty segments",
+                prefix
+            );
+        }
+        if self.nested.iter().any(|(p, _)| p == prefix) {
+            bail!("prefix {} already mounted", prefix);
+        }
+        if let Some((name, _)) = router
+            .urls
+            .iter()
+            .find(|(name, _)| self.urls.contains(name))
+        {
+            bail!("route name {} already in use", name);
+        }
+
+        // the names of the nested router are reachable through this one, under the prefix
+        for (name, route) in router.urls.iter() {
+            let mut segments = segments.clone();
+            // the root of the nested router is the prefix itself
+            if route.as_slice() != [Segment::Static(String::new())] {
+                segments.extend(route.iter().cloned());
+            }
+            self.urls.insert(name, segments)?;
         }
 
-        // will never return Some as we pre-check above
-        self.endpoints.insert(route, handler);
+        self.nested.push((prefix.to_string(), router));
+        // longest prefix first, so /api/admin is preferred over /api
+        self.nested.sort_by_key(|(p, _)| Reverse(p.len()));
 
         Ok(())
     }
 
+    // every route of this and the nested routers, sorted by template and method
+    // the default handlers and the automatic OPTIONS answers are not listed
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        let layers = self.middleware.iter().map(|m| m.name());
+        let mut routes: Vec<RouteInfo> = self
+            .routes
+            .endpoints()
+            .into_iter()
+            .flat_map(|(template, method, candidates)| {
+                candidates
+                    .iter()
+                    .map(move |endpoint| (template, method, endpoint))
+            })
+            .map(|(template, method, endpoint)| RouteInfo {
+                method,
+                template: template.to_string(),
+                name: endpoint.name.clone(),
+                middleware: layers
+                    .clone()
+                    .chain(endpoint.middleware.iter().map(|m| m.name()))
+                    .collect(),
+                guards: endpoint.guards.iter().map(|g| g.name()).collect(),
+                docs: endpoint.docs.clone(),
+            })
+            .collect();
+
+        for (prefix, router) in &self.nested {
+            for mut route in router.routes() {
+                route.template = match route.template.as_str() {
+                    "/" => prefix.clone(),
+                    template => format!("{}{}", prefix, template),
+                };
+                route.middleware.splice(0..0, layers.clone());
+                routes.push(route);
+            }
+        }
+
+        routes.sort_by(|a, b| (&a.template, a.method).cmp(&(&b.template, b.method)));
+        routes
+    }
+
+    // the URL of a named route, see Urls::url_for
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        self.urls.url_for(name, params)
+    }
+
     // executes a request
+    // if a nested router owns the target, it handles the request
     // if no sufficient target is found, default will be executed
-    // if any handler fails, an internal server error is returned
-    pub fn execute(&self, method: Method, target: &String, request: &Request) -> Response {
-        // todo pass actual matched wildcard (if any)
-        let route = self
-            .endpoints
-            .iter()
-            .map(|(p, c)| (Router::match_route(p, method, target), c)) // find matching endpoints
-            .filter(|(m, _)| matches!(m, RouteMatch::Match(_)))
-            // select the first match
-            .next();
+    // if the target is known for other methods only, 405 is returned (or 204 for OPTIONS)
+    // if the guards of every matching route refuse the request, the rejection status is returned
+    // handler errors are answered by their IntoResponse implementation,
+    // an anyhow::Error without an HttpError inside becomes an internal server error
+    pub fn execute(&self, request: Request) -> BoxFuture<'_, Response> {
+        self.execute_with(request, None)
+    }
 
-        let (replaced_path, callback) =
-            route.map_or((String::new(), &self.default), |(route_match, cb)| {
-                (
-                    match route_match {
-                        RouteMatch::Match(m) => m.unwrap_or(String::new()),
-                        RouteMatch::NoMatch => String::new(),
-                    },
-                    cb,
-                )
-            });
-
-        callback(request, replaced_path, &self.ctx).unwrap_or(Response::internal_error())
-    }
-
-    fn match_route(path: &String, method: Method, target: &String) -> RouteMatch {
-        let regex = Regex::new(PATTERN).expect("regex issue");
-        let route = Router::route_identifier(method, target);
-        if regex.is_match(path) {
-            // our registered endpoint is a wildcard
-
-            // create a regex pattern based on the wildcard endpoint
-            let re = Regex::new(r"\{[^{}]*\}").expect("invalid regex");
-            let pattern = re
-                .replace_all(path.replace("/", r"\/").as_str(), r"(.+)")
-                .to_string();
-
-            let actual_route_regex = Regex::new(pattern.as_str()).expect("invalid regex");
-            if let Some(captures) = actual_route_regex.captures(&route) {
-                return RouteMatch::Match(Some(captures.get(1).unwrap().as_str().to_string()));
+    // executes a request received from peer, which handlers can extract with PeerAddr
+    pub fn execute_from(&self, request: Request, peer: SocketAddr) -> BoxFuture<'_, Response> {
+        self.execute_with(request, Some(peer))
+    }
+
+    fn execute_with(&self, request: Request, peer: Option<SocketAddr>) -> BoxFuture<'_, Response> {
+        self.run(request, peer, &self.urls, String::new())
+    }
+
+    // urls are those of the router the request arrived at, they know about all nested routes
+    // base is the part of the path stripped by the routers this one is nested in
+    fn run<'a>(
+        &'a self,
+        request: Request,
+        peer: Option<SocketAddr>,
+        urls: &'a Urls,
+        base: String,
+    ) -> BoxFuture<'a, Response> {
+        Next::new(&self.middleware, move |request| {
+            self.dispatch(request, peer, urls, base)
+        })
+        .run(request)
+    }
+
+    fn dispatch<'a>(
+        &'a self,
+        mut request: Request,
+        peer: Option<SocketAddr>,
+        urls: &'a Urls,
+        base: String,
+    ) -> BoxFuture<'a, Response> {
+        Box::pin(async move {
+            let method = request.start_line.method;
+            let target = request.start_line.target.clone();
+            // the query string is not part of the route
+            let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+            for (prefix, router) in &self.nested {
+                let Some(rest) = self.strip_prefix(path, prefix) else {
+                    continue;
+                };
+                if !rest.is_empty() && !rest.starts_with('/') {
+                    continue;
+                }
+
+                let mut relative = match rest {
+                    "" => "/".to_string(),
+                    rest => rest.to_string(),
+                };
+                if !query.is_empty() {
+                    relative.push('?');
+                    relative.push_str(query);
+                }
+
+                request.start_line.target = relative;
+                let base = format!("{}{}", base, &path[..prefix.len()]);
+                return router.run(request, peer, urls, base).await;
+            }
+
+            // remembers which candidate was accepted, so guards run once per endpoint
+            let (found, accepted) = {
+                let accepted = Cell::new(0);
+                let accept = |candidates: &Vec<Endpoint<S>>| match candidates
+                    .iter()
+                    .position(|e| e.accepts(&request))
+                {
+                    Some(index) => {
+                        accepted.set(index);
+                        true
+                    }
+                    None => false,
+                };
+                let mut found = self
+                    .routes
+                    .lookup(path, method, self.case_insensitive, &accept);
+                if let (Match::NotFound, Some(alternate)) = (&found, self.alternate(path)) {
+                    match self
+                        .routes
+                        .lookup(&alternate, method, self.case_insensitive, &accept)
+                    {
+                        Match::NotFound => {}
+                        _ if self.trailing_slash == TrailingSlash::Redirect => {
+                            let mut location = format!("{}{}", base, alternate);
+                            if !query.is_empty() {
+                                location.push('?');
+                                location.push_str(query);
+                            }
+                            return redirect(Redirection::PermanentRedirect, location);
+                        }
+                        alternate => found = alternate,
+                    }
+                }
+
+                (found, accepted.get())
+            };
+
+            match found {
+                // the lookup stops at the first candidates it accepts
+                Match::Found(candidates, params) => {
+                    let endpoint = &candidates[accepted];
+                    Next::new(&endpoint.middleware, |request| {
+                        self.call(&endpoint.callback, request, peer, params, urls)
+                    })
+                    .run(request)
+                    .await
+                }
+                Match::MethodNotAllowed(allowed) => not_allowed(method, allowed),
+                Match::Rejected => self.rejection.into_response(),
+                Match::NotFound => {
+                    self.call(&self.default, request, peer, Params::default(), urls)
+                        .await
+                }
+            }
+        })
+    }
+
+    fn strip_prefix<'p>(&self, path: &'p str, prefix: &str) -> Option<&'p str> {
+        match self.case_insensitive {
+            true => path
+                .get(..prefix.len())
+                .filter(|p| p.eq_ignore_ascii_case(prefix))
+                .map(|_| &path[prefix.len()..]),
+            false => path.strip_prefix(prefix),
+        }
+    }
+
+    // the path with its trailing slash toggled, if the policy allows matching it
+    fn alternate(&self, path: &str) -> Option<String> {
+        match (self.trailing_slash, path) {
+            (TrailingSlash::Strict, _) | (_, "/") => None,
+            (_, path) => match path.strip_suffix('/') {
+                Some(path) => Some(path.to_string()),
+                None => Some(format!("{}/", path)),
+            },
+        }
+    }
+
+    fn call(
+        &self,
+        callback: &Callback<S>,
+        request: Request,
+        peer: Option<SocketAddr>,
+        params: Params,
+        urls: &Urls,
+    ) -> BoxFuture<'static, Response> {
+        callback.call(request, peer, params, self.state.clone(), urls.clone())
+    }
+}
+
+fn redirect(status: Redirection, location: String) -> Response {
+    let headers = HashMap::from([("Location".to_string(), location)]);
+    Response::new(
+        StatusLine::new(Version::Http1_1, Status::Redirection(status)),
+        headers,
+        None,
+    )
+}
+
+fn not_allowed(method: Method, mut allowed: Vec<Method>) -> Response {
+    // OPTIONS is answered for every known path
+    if !allowed.contains(&Method::Options) {
+        allowed.push(Method::Options);
+    }
+    let allow = allowed
+        .into_iter()
+        .map(Into::<String>::into)
+        .collect::<Vec<String>>()
+        .join(", ");
+    let headers = HashMap::from([("Allow".to_string(), allow)]);
+
+    match method {
+        Method::Options => Response::no_content(headers),
+        _ => Response::method_not_allowed(headers),
+    }
+}
+
+impl<S: 'static> Route<S> {
+    pub fn new<T: 'static>(method: Method, template: String, handler: impl Handler<T, S>) -> Self {
+        Self {
+            method,
+            template,
+            name: None,
+            docs: Docs::default(),
+            callback: handler::erase(handler),
+            guards: Vec::new(),
+            middleware: Vec::new(),
+        }
+    }
+
+    // names the route, so its URL can be generated with Router::url_for or the Urls extractor
+    pub fn name(mut self, name: &str) -> Self {
+        self.name = Some(name.to_string());
+        self
+    }
+
+    // a short description for the OpenAPI document
+    pub fn summary(mut self, summary: &str) -> Self {
+        self.docs.summary = Some(summary.to_string());
+        self
+    }
+
+    // documents the expected request body, schema is a JSON schema
+    pub fn request_body(mut self, media_type: &str, schema: Value) -> Self {
+        self.docs.request_body = Some(Content {
+            media_type: media_type.to_string(),
+            schema,
+        });
+        self
+    }
+
+    // documents a possible response, may be called for several statuses and media types
+    pub fn response(mut self, status: Status, media_type: &str, schema: Value) -> Self {
+        let content = Content {
+            media_type: media_type.to_string(),
+            schema,
+        };
+        self.docs.responses.push((status, Some(content)));
+        self
+    }
+
+    // documents a possible response without body
+    pub fn empty_response(mut self, status: Status) -> Self {
+        self.docs.responses.push((status, None));
+        self
+    }
+
+    // the route only handles requests accepted by guard, may be called several times
+    pub fn guard(mut self, guard: impl Guard + 'static) -> Self {
+        self.guards.push(Box::new(guard));
+        self
+    }
+
+    // wraps this route only, after the layers of its router
+    pub fn layer(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+}
+
+// splits a route template like /users/{id}/posts/{post} into its segments
+fn parse_template(template: &str) -> Result<Vec<Segment>> {
+    let Some(path) = template.strip_prefix('/') else {
+        bail!("route {} must start with /", template);
+    };
+
+    let mut segments = Vec::new();
+    let mut names = Vec::new();
+    let mut parts = path.split('/').peekable();
+    while let Some(segment) = parts.next() {
+        let Some(name) = segment.strip_prefix('{').and_then(|s| s.strip_suffix('}')) else {
+            if segment.contains(['{', '}']) {
+                bail!("parameter {} must span a whole segment", segment);
             }
+            segments.push(Segment::Static(segment.to_string()));
+            continue;
+        };
+
+        let (name, catch_all) = match name.strip_prefix('*') {
+            Some(name) => (name, true),
+            None => (name, false),
+        };
+        let (name, constraint) = match name.split_once(':') {
+            Some((name, spec)) => (name, Some(Constraint::parse(spec)?)),
+            None => (name, None),
+        };
+        if name.is_empty() || name.contains(['{', '}', '*']) {
+            bail!("invalid parameter {} in route {}", segment, template);
+        }
+        if names.contains(&name) {
+            bail!("duplicate parameter {} in route {}", name, template);
+        }
+        names.push(name);
+
+        if !catch_all {
+            segments.push(Segment::Param(name.to_string(), constraint));
+        } else if constraint.is_some() {
+            bail!("catch-all {} cannot be constrained", segment);
+        } else if parts.peek().is_none() {
+            segments.push(Segment::CatchAll(name.to_string()));
         } else {
-            // endpoint is static
-            if path == &route {
-                return RouteMatch::Match(None);
+            bail!(
+                "catch-all {} must be the last segment of {}",
+                segment,
+                template
+            );
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Instant,
+    };
+
+    use crate::http::message::{
+        message::Version,
+        request::{Method, Request, RequestLine},
+        response::{ClientError, HttpError, Response, Status, Successful},
+    };
+
+    use anyhow::Result;
+
+    use super::{
+        extract::{Headers, Json, PeerAddr, Query, State},
+        guard::{ContentType, Header, QueryFlag},
+        parse_template, BoxFuture, Constraint, Middleware, Next, Params, Route, Router, Segment,
+        TrailingSlash, Urls,
+    };
+
+    #[test]
+    fn template() {
+        assert_eq!(
+            parse_template("/users/{id}").unwrap(),
+            vec![
+                Segment::Static("users".to_string()),
+                Segment::Param("id".to_string(), None)
+            ]
+        );
+        assert_eq!(
+            parse_template("/items/{id:u64}").unwrap()[1],
+            Segment::Param("id".to_string(), Some(Constraint::U64))
+        );
+        assert_eq!(
+            parse_template("/slug/{s:[a-z-]+}").unwrap()[1],
+            Segment::Param("s".to_string(), Some(Constraint::parse("[a-z-]+").unwrap()))
+        );
+        assert!(parse_template("/items/{id:[a-z}").is_err());
+        assert!(parse_template("/files/{*path:u64}").is_err());
+        assert!(parse_template("users").is_err());
+        assert!(parse_template("/users/{}").is_err());
+        assert!(parse_template("/users/a{id}").is_err());
+        assert!(parse_template("/{id}/{id}").is_err());
+
+        assert_eq!(
+            parse_template("/files/{*path}").unwrap(),
+            vec![
+                Segment::Static("files".to_string()),
+                Segment::CatchAll("path".to_string())
+            ]
+        );
+        assert!(parse_template("/files/{*path}/raw").is_err());
+        assert!(parse_template("/files/{*}").is_err());
+    }
+
+    fn router() -> Router<()> {
+        Router::new(not_found, ())
+    }
+
+    fn request(method: Method, target: &str) -> Request {
+        Request::new(
+            RequestLine::new(method, target.to_string(), Version::Http1_1),
+            HashMap::new(),
+            None,
+        )
+    }
+
+    async fn execute(router: &Router<()>, method: Method, target: &str) -> Response {
+        router.execute(request(method, target)).await
+    }
+
+    async fn not_found() -> Result<Response> {
+        Ok(Response::not_found())
+    }
+
+    async fn ok() -> Result<Response> {
+        Ok(Response::ok(HashMap::new(), None))
+    }
+
+    async fn target_and_path(request: Request, params: Params) -> Result<Response> {
+        let body = format!(
+            "{} {}",
+            request.start_line.target,
+            params.get("path").unwrap()
+        );
+        Ok(Response::ok(HashMap::new(), Some(body)))
+    }
+
+    // appends its name to the X-Trace header on the way in and on the way out
+    struct Trace(&'static str);
+
+    impl Middleware for Trace {
+        fn call<'a>(&'a self, mut request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+            Box::pin(async move {
+                let trace = request.headers.entry("X-Trace".to_string()).or_default();
+                trace.push_str(self.0);
+                let seen = trace.clone();
+
+                let mut response = next.run(request).await;
+                let trace = response
+                    .headers
+                    .entry("X-Trace".to_string())
+                    .or_insert(seen);
+                trace.push_str(self.0);
+                response
+            })
+        }
+    }
+
+    fn require_authorization(request: Request, next: Next<'_>) -> BoxFuture<'_, Response> {
+        Box::pin(async move {
+            match request.header("Authorization") {
+                Some(_) => next.run(request).await,
+                None => Response::not_found(),
             }
+        })
+    }
+
+    // routers are Send and Sync for any state that is, without unsafe impls
+    #[test]
+    fn shareable() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        fn router_send_sync<S: Send + Sync>() {
+            assert_send_sync::<Router<S>>();
+        }
+        router_send_sync::<()>();
+        router_send_sync::<Arc<String>>();
+    }
+
+    #[tokio::test]
+    async fn nested() {
+        let mut files = router();
+        files
+            .add(Method::Get, "/{*path}".to_string(), target_and_path)
+            .unwrap();
+
+        let mut router = router();
+        router
+            .add(Method::Get, "/filesystem".to_string(), ok)
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        let response = execute(&router, Method::Get, "/files/a/b.txt?raw=1").await;
+        assert_eq!(response.body.unwrap(), "/a/b.txt?raw=1 a/b.txt");
+
+        let response = execute(&router, Method::Get, "/filesystem").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 200);
+
+        // the nested default answers for its own prefix
+        let response = execute(&router, Method::Get, "/files").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 404);
+
+        assert!(router.nest("/files", self::router()).is_err());
+        assert!(router.nest("/{id}", self::router()).is_err());
+    }
+
+    #[tokio::test]
+    async fn named_routes() {
+        async fn location(urls: Urls, params: Params) -> Result<Response> {
+            let url = urls.url_for("file", &[("path", params.get("path").unwrap())])?;
+            Ok(Response::ok(HashMap::new(), Some(url)))
         }
 
-        RouteMatch::NoMatch
+        let mut files = router();
+        files
+            .route(Route::new(Method::Get, "/".to_string(), ok).name("files"))
+            .unwrap();
+        files
+            .route(Route::new(Method::Post, "/{*path}".to_string(), location).name("file"))
+            .unwrap();
+
+        let mut router = router();
+        router
+            .route(Route::new(Method::Get, "/items/{id:u64}".to_string(), ok).name("item"))
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        assert_eq!(router.url_for("item", &[("id", "1")]).unwrap(), "/items/1");
+        assert_eq!(router.url_for("files", &[]).unwrap(), "/files");
+        assert!(router.url_for("item", &[("id", "one")]).is_err());
+
+        // nested handlers generate URLs including the prefix
+        let response = execute(&router, Method::Post, "/files/a/b c").await;
+        assert_eq!(response.body.unwrap(), "/files/a/b%20c");
+
+        let duplicate = Route::new(Method::Get, "/other".to_string(), ok).name("item");
+        assert!(router.route(duplicate).is_err());
+        let mut other = self::router();
+        other
+            .route(Route::new(Method::Get, "/".to_string(), ok).name("files"))
+            .unwrap();
+        assert!(router.nest("/other", other).is_err());
+    }
+
+    #[test]
+    fn route_listing() {
+        let mut files = router();
+        files.layer(Trace("b"));
+        files
+            .route(Route::new(Method::Get, "/".to_string(), ok).name("files"))
+            .unwrap();
+        files
+            .route(
+                Route::new(Method::Post, "/{*path}".to_string(), ok).layer(require_authorization),
+            )
+            .unwrap();
+
+        let mut router = router();
+        router.layer(Trace("a"));
+        router
+            .add(Method::Get, "/{id:u64}".to_string(), ok)
+            .unwrap();
+        router
+            .add(Method::Delete, "/{id:u64}".to_string(), ok)
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        let trace = std::any::type_name::<Trace>();
+        let listing: Vec<_> = router
+            .routes()
+            .into_iter()
+            .map(|r| (r.method, r.template, r.name, r.middleware))
+            .collect();
+        assert_eq!(
+            listing,
+            vec![
+                (
+                    Method::Get,
+                    "/files".to_string(),
+                    Some("files".to_string()),
+                    vec![trace, trace]
+                ),
+                (
+                    Method::Post,
+                    "/files/{*path}".to_string(),
+                    None,
+                    vec![
+                        trace,
+                        trace,
+                        std::any::type_name_of_val(&require_authorization)
+                    ]
+                ),
+                (Method::Get, "/{id:u64}".to_string(), None, vec![trace]),
+                (Method::Delete, "/{id:u64}".to_string(), None, vec![trace]),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn trailing_slash() {
+        let mut files = router();
+        files.add(Method::Get, "/{name}".to_string(), ok).unwrap();
+        files
+            .add(Method::Get, "/docs/{page}/".to_string(), ok)
+            .unwrap();
+        files.trailing_slash(TrailingSlash::Redirect);
+
+        let mut router = router();
+        router
+            .add(Method::Get, "/user-agent".to_string(), ok)
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        let status = |response: &Response| Into::<u16>::into(response.start_line.status());
+
+        // strict by default
+        let response = execute(&router, Method::Get, "/user-agent/").await;
+        assert_eq!(status(&response), 404);
+
+        router.trailing_slash(TrailingSlash::MatchBoth);
+        let response = execute(&router, Method::Get, "/user-agent/").await;
+        assert_eq!(status(&response), 200);
+        let response = execute(&router, Method::Post, "/user-agent/").await;
+        assert_eq!(status(&response), 405);
+
+        // redirects keep the prefix of the router they are nested in and the query
+        let response = execute(&router, Method::Get, "/files/a.txt/?raw=1").await;
+        assert_eq!(status(&response), 308);
+        assert_eq!(response.header("Location").unwrap(), "/files/a.txt?raw=1");
+        let response = execute(&router, Method::Get, "/files/docs/intro").await;
+        assert_eq!(status(&response), 308);
+        assert_eq!(response.header("Location").unwrap(), "/files/docs/intro/");
+        let response = execute(&router, Method::Get, "/files/docs/intro/").await;
+        assert_eq!(status(&response), 200);
     }
 
-    fn route_identifier(method: Method, target: &String) -> String {
-        let mut identifier = String::new();
-        let method = Into::<String>::into(method);
+    #[tokio::test]
+    async fn case_insensitive() {
+        let mut files = router();
+        files
+            .add(Method::Get, "/{*path}".to_string(), target_and_path)
+            .unwrap();
+
+        let mut router = router();
+        router
+            .add(Method::Get, "/echo/{text}".to_string(), ok)
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        let response = execute(&router, Method::Get, "/ECHO/abc").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 404);
+
+        router.case_insensitive(true);
+        let response = execute(&router, Method::Get, "/ECHO/abc").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 200);
 
-        identifier.push_str(&method);
-        identifier.push_str(target);
+        // parameters keep their case
+        let response = execute(&router, Method::Get, "/Files/A.txt").await;
+        assert_eq!(response.body.unwrap(), "/A.txt A.txt");
+    }
+
+    #[tokio::test]
+    async fn guards() {
+        async fn v2() -> Result<Response> {
+            Ok(Response::ok(HashMap::new(), Some("v2".to_string())))
+        }
+
+        async fn json() -> Result<Response> {
+            Ok(Response::ok(HashMap::new(), Some("json".to_string())))
+        }
+
+        let mut router = router();
+        router
+            .route(
+                Route::new(Method::Get, "/items".to_string(), v2)
+                    .guard(Header("X-Api-Version", "2")),
+            )
+            .unwrap();
+        router
+            .route(
+                Route::new(Method::Get, "/items".to_string(), json)
+                    .guard(QueryFlag("json"))
+                    .guard(|request: &Request| request.header("Accept").is_some()),
+            )
+            .unwrap();
+        router.add(Method::Get, "/items".to_string(), ok).unwrap();
+        assert!(router.add(Method::Get, "/items".to_string(), ok).is_err());
+        router
+            .route(
+                Route::new(Method::Post, "/items".to_string(), ok)
+                    .guard(ContentType("application/json")),
+            )
+            .unwrap();
+
+        let get = |headers: &[(&str, &str)], target: &str| {
+            let mut request = request(Method::Get, target);
+            for (k, v) in headers {
+                request.headers.insert(k.to_string(), v.to_string());
+            }
+            router.execute(request)
+        };
+
+        let response = get(&[("x-api-version", "2")], "/items?json").await;
+        assert_eq!(response.body.unwrap(), "v2");
+        let response = get(&[("Accept", "*/*")], "/items?json").await;
+        assert_eq!(response.body.unwrap(), "json");
+        // the unguarded route is the fallback
+        let response = get(&[], "/items?json").await;
+        assert_eq!(response.body, None);
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 200);
+
+        let response = execute(&router, Method::Post, "/items").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 404);
+
+        router.rejection(Status::ClientError(ClientError::UnsupportedMediaType));
+        let response = execute(&router, Method::Post, "/items").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 415);
+
+        // guards run once per request, not again before the handler
+        let checks = Arc::new(AtomicUsize::new(0));
+        let counted = Arc::clone(&checks);
+        router
+            .route(
+                Route::new(Method::Get, "/counted".to_string(), ok).guard(move |_: &Request| {
+                    counted.fetch_add(1, Ordering::SeqCst);
+                    true
+                }),
+            )
+            .unwrap();
+        execute(&router, Method::Get, "/counted").await;
+        assert_eq!(checks.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn middleware_order() {
+        let mut files = router();
+        files.layer(Trace("b"));
+        files
+            .route(Route::new(Method::Get, "/{name}".to_string(), ok).layer(Trace("c")))
+            .unwrap();
+
+        let mut router = router();
+        router.layer(Trace("a"));
+        router.nest("/files", files).unwrap();
+
+        let response = execute(&router, Method::Get, "/files/a.txt").await;
+        assert_eq!(response.header("X-Trace").unwrap(), "abccba");
+
+        // router middleware also wraps the default handler
+        let response = execute(&router, Method::Get, "/unknown").await;
+        assert_eq!(response.header("X-Trace").unwrap(), "aa");
+    }
+
+    #[tokio::test]
+    async fn middleware_short_circuit() {
+        let mut router = router();
+        router.add(Method::Get, "/".to_string(), ok).unwrap();
+        router.layer(require_authorization);
+
+        let response = execute(&router, Method::Get, "/").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 404);
+
+        let mut request = request(Method::Get, "/");
+        request
+            .headers
+            .insert("Authorization".to_string(), "yes".to_string());
+        let response = router.execute(request).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 200);
+    }
+
+    #[tokio::test]
+    async fn method_not_allowed() {
+        let mut router = router();
+        router
+            .add(Method::Get, "/user-agent".to_string(), ok)
+            .unwrap();
+
+        let response = execute(&router, Method::Post, "/user-agent").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 405);
+        assert_eq!(response.header("Allow").unwrap(), "GET, OPTIONS");
+
+        let response = execute(&router, Method::Options, "/user-agent").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 204);
+        assert_eq!(response.header("Allow").unwrap(), "GET, OPTIONS");
+
+        let response = execute(&router, Method::Post, "/unknown").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 404);
+    }
+
+    #[tokio::test]
+    async fn extractors() {
+        async fn greet(
+            Query(query): Query,
+            PeerAddr(peer): PeerAddr,
+            State(greeting): State<&'static str>,
+        ) -> Result<Response> {
+            let body = format!("{} {} from {}", greeting, query["name"], peer.ip());
+            Ok(Response::ok(HashMap::new(), Some(body)))
+        }
+
+        async fn sum(Json(values): Json<Vec<u64>>) -> Result<Response> {
+            let body = values.iter().sum::<u64>().to_string();
+            Ok(Response::ok(HashMap::new(), Some(body)))
+        }
+
+        let mut router = Router::new(|| async { Response::not_found() }, "hello");
+        router
+            .add(Method::Get, "/greet".to_string(), greet)
+            .unwrap();
+        router.add(Method::Post, "/sum".to_string(), sum).unwrap();
+
+        let greeting = request(Method::Get, "/greet?name=J%C3%BCrgen");
+        let peer = "127.0.0.1:1234".parse().unwrap();
+        let response = router.execute_from(greeting, peer).await;
+        assert_eq!(response.body.unwrap(), "hello Jürgen from 127.0.0.1");
+
+        // no peer address outside of a connection
+        let response = router.execute(request(Method::Get, "/greet?name=x")).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 500);
+
+        let sum = |content_type: &str, body: &str| {
+            Request::new(
+                RequestLine::new(Method::Post, "/sum".to_string(), Version::Http1_1),
+                HashMap::from([("content-type".to_string(), content_type.to_string())]),
+                Some(body.to_string()),
+            )
+        };
+        let response = router.execute(sum("application/json", "[1, 2, 3]")).await;
+        assert_eq!(response.body.unwrap(), "6");
+
+        let response = router.execute(sum("application/json", "[1, ")).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 400);
+
+        let response = router.execute(sum("text/plain", "[1, 2, 3]")).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 415);
+    }
+
+    #[tokio::test]
+    async fn handler_errors() {
+        async fn missing(headers: Headers) -> Result<Response> {
+            let name = headers
+                .get("X-Name")
+                .ok_or_else(|| HttpError::bad_request("X-Name header required"))?;
+            Ok(Response::ok(HashMap::new(), Some(name.clone())))
+        }
+
+        async fn failing() -> Result<Response> {
+            anyhow::bail!("disk on fire")
+        }
+
+        async fn plain() -> (Status, &'static str) {
+            (Status::Successful(Successful::Created), "done")
+        }
+
+        let mut router = router();
+        router
+            .add(Method::Get, "/missing".to_string(), missing)
+            .unwrap();
+        router
+            .add(Method::Get, "/failing".to_string(), failing)
+            .unwrap();
+        router
+            .add(Method::Get, "/plain".to_string(), plain)
+            .unwrap();
+
+        let response = execute(&router, Method::Get, "/missing").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 400);
+        assert_eq!(response.body.unwrap(), "X-Name header required");
+
+        let response = execute(&router, Method::Get, "/failing").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 500);
+        assert_eq!(response.body, None);
+
+        let response = execute(&router, Method::Get, "/plain").await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 201);
+        assert_eq!(response.body.unwrap(), "done");
+    }
+
+    // cargo test --release -- --ignored --nocapture routing_throughput
+    #[tokio::test]
+    #[ignore]
+    async fn routing_throughput() {
+        const ROUTES: usize = 1_000;
+        const REQUESTS: usize = 1_000_000;
+
+        let mut router = router();
+        for i in 0..ROUTES {
+            let template = match i % 2 {
+                0 => format!("/resource{}/items", i),
+                _ => format!("/resource{}/{{id}}/items/{{item}}", i),
+            };
+            router.add(Method::Get, template, ok).unwrap();
+        }
+
+        let requests: Vec<Request> = (0..ROUTES)
+            .map(|i| {
+                let target = match i % 2 {
+                    0 => format!("/resource{}/items", i),
+                    _ => format!("/resource{}/42/items/7?page=1", i),
+                };
+                request(Method::Get, &target)
+            })
+            .collect();
+
+        let start = Instant::now();
+        for request in requests.iter().cycle().take(REQUESTS) {
+            let response = router.execute(request.clone()).await;
+            assert_eq!(Into::<u16>::into(response.start_line.status()), 200);
+        }
+        let elapsed = start.elapsed();
 
-        identifier
+        println!(
+            "{} routes: {} requests in {:?} ({:.0} requests/s)",
+            ROUTES,
+            REQUESTS,
+            elapsed,
+            REQUESTS as f64 / elapsed.as_secs_f64()
+        );
     }
 }
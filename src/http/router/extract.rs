@@ -0,0 +1,209 @@
+use std::{collections::HashMap, net::SocketAddr};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::http::message::{
+    request::Request,
+    response::{ClientError, HttpError, IntoResponse, Response, ServerError, Status},
+};
+
+use super::{ContentType, Guard, Params, Urls};
+
+// everything a handler argument can be extracted from
+pub struct RequestParts<'a, S> {
+    pub request: &'a Request,
+    pub params: &'a Params,
+    pub state: &'a S,
+    pub urls: &'a Urls,
+    // the client the request was received from, None outside of a connection
+    pub peer: Option<SocketAddr>,
+}
+
+// a handler argument, extracted before the handler is called
+// a failed extraction answers the request with the rejection instead
+pub trait FromRequest<S>: Sized {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection>;
+}
+
+// extractors reject a request with the status and message of an HttpError
+pub type Rejection = HttpError;
+
+// the whole request
+impl<S> FromRequest<S> for Request {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(parts.request.clone())
+    }
+}
+
+// all path parameters of the matched route
+impl<S> FromRequest<S> for Params {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(parts.params.clone())
+    }
+}
+
+// a clone of the router state
+pub struct State<S>(pub S);
+
+impl<S: Clone> FromRequest<S> for State<S> {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(State(parts.state.clone()))
+    }
+}
+
+// the named routes, to generate URLs for responses
+impl<S> FromRequest<S> for Urls {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(parts.urls.clone())
+    }
+}
+
+// the decoded query string, later keys win
+pub struct Query(pub HashMap<String, String>);
+
+impl<S> FromRequest<S> for Query {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        let query = match parts.request.start_line.target.split_once('?') {
+            Some((_, query)) => parse_query(query)?,
+            None => HashMap::new(),
+        };
+
+        Ok(Query(query))
+    }
+}
+
+pub struct Headers(pub HashMap<String, String>);
+
+impl Headers {
+    // header names are case-insensitive
+    pub fn get(&self, name: &str) -> Option<&String> {
+        self.0
+            .iter()
+            .find(|(k, _)| k.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+}
+
+impl<S> FromRequest<S> for Headers {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(Headers(parts.request.headers.clone()))
+    }
+}
+
+// the raw body, empty if the request has none
+pub struct Body(pub String);
+
+impl<S> FromRequest<S> for Body {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        Ok(Body(parts.request.body.clone().unwrap_or_default()))
+    }
+}
+
+// a body of Content-Type application/json, deserialized into T
+// as a return value, T is serialized into the body of a 200
+pub struct Json<T>(pub T);
+
+impl<S, T: DeserializeOwned> FromRequest<S> for Json<T> {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        if !ContentType("application/json").check(parts.request) {
+            return Err(Rejection::new(
+                Status::ClientError(ClientError::UnsupportedMediaType),
+                "expected Content-Type application/json",
+            ));
+        }
+
+        let body = parts.request.body.as_deref().unwrap_or_default();
+        serde_json::from_str(body)
+            .map(Json)
+            .map_err(|e| Rejection::bad_request(format!("invalid JSON body: {}", e)))
+    }
+}
+
+// a JSON response, serialization failures are internal server errors
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        let body = match serde_json::to_string(&self.0) {
+            Ok(body) => body,
+            Err(e) => return anyhow::Error::from(e).into_response(),
+        };
+        let headers = HashMap::from([
+            ("Content-Type".to_string(), "application/json".to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ]);
+
+        Response::ok(headers, Some(body))
+    }
+}
+
+// the address of the connected client
+pub struct PeerAddr(pub SocketAddr);
+
+impl<S> FromRequest<S> for PeerAddr {
+    fn from_request(parts: &RequestParts<'_, S>) -> Result<Self, Rejection> {
+        // only requests that did not come in over a connection lack an address
+        parts.peer.map(PeerAddr).ok_or_else(|| {
+            Rejection::new(
+                Status::ServerError(ServerError::Internal),
+                "peer address unknown",
+            )
+        })
+    }
+}
+
+fn parse_query(query: &str) -> Result<HashMap<String, String>, Rejection> {
+    query
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            Ok((decode(key)?, decode(value)?))
+        })
+        .collect()
+}
+
+// percent-decoding, with + as space as in HTML forms
+fn decode(value: &str) -> Result<String, Rejection> {
+    let invalid = || Rejection::bad_request(format!("invalid query string encoding: {}", value));
+
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(byte) = input.next() {
+        match byte {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let hex = [
+                    input.next().ok_or_else(invalid)?,
+                    input.next().ok_or_else(invalid)?,
+                ];
+                let hex = std::str::from_utf8(&hex).map_err(|_| invalid())?;
+                bytes.push(u8::from_str_radix(hex, 16).map_err(|_| invalid())?);
+            }
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| invalid())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::parse_query;
+
+    #[test]
+    fn query() {
+        let query = parse_query("name=J%C3%BCrgen+M&empty&x=1&x=2").unwrap();
+
+        assert_eq!(
+            query,
+            HashMap::from([
+                ("name".to_string(), "Jürgen M".to_string()),
+                ("empty".to_string(), String::new()),
+                ("x".to_string(), "2".to_string()),
+            ])
+        );
+        assert!(parse_query("a=%zz").is_err());
+        assert!(parse_query("a=%c3").is_err());
+    }
+}
@@ -0,0 +1,60 @@
+use crate::http::message::request::Request;
+
+// decides whether a route handles a request, checked after method and path matched
+// several routes may share method and template if they are guarded, see Route::guard
+pub trait Guard: Send + Sync {
+    fn check(&self, request: &Request) -> bool;
+
+    // shown in route listings, the type (or function) name unless overridden
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// allows plain functions and closures as guards: fn is_admin(request: &Request) -> bool
+impl<F> Guard for F
+where
+    F: Fn(&Request) -> bool + Send + Sync,
+{
+    fn check(&self, request: &Request) -> bool {
+        self(request)
+    }
+}
+
+// the header is present with the given value (compared case-insensitively)
+pub struct Header(pub &'static str, pub &'static str);
+
+impl Guard for Header {
+    fn check(&self, request: &Request) -> bool {
+        request
+            .header(self.0)
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case(self.1))
+    }
+}
+
+// the media type of the body, parameters like charset are ignored
+pub struct ContentType(pub &'static str);
+
+impl Guard for ContentType {
+    fn check(&self, request: &Request) -> bool {
+        request
+            .header("Content-Type")
+            .and_then(|v| v.split(';').next())
+            .is_some_and(|v| v.trim().eq_ignore_ascii_case(self.0))
+    }
+}
+
+// the query string contains the key, with or without value
+pub struct QueryFlag(pub &'static str);
+
+impl Guard for QueryFlag {
+    fn check(&self, request: &Request) -> bool {
+        let Some((_, query)) = request.start_line.target.split_once('?') else {
+            return false;
+        };
+
+        query
+            .split('&')
+            .any(|pair| pair.split_once('=').map_or(pair, |(key, _)| key) == self.0)
+    }
+}
@@ -0,0 +1,114 @@
+use std::{future::Future, marker::PhantomData, net::SocketAddr};
+
+use crate::http::message::{
+    request::Request,
+    response::{IntoResponse, Response},
+};
+
+use super::{
+    extract::{FromRequest, RequestParts},
+    BoxFuture, Params, Urls,
+};
+
+// an async request handler, implemented for every
+// async fn(T1, T2, ...) -> impl IntoResponse
+// where every argument can be extracted from the request (see FromRequest)
+pub trait Handler<T, S>: Send + Sync + 'static {
+    fn call(
+        &self,
+        request: Request,
+        peer: Option<SocketAddr>,
+        params: Params,
+        state: S,
+        urls: Urls,
+    ) -> BoxFuture<'static, Response>;
+}
+
+macro_rules! impl_handler {
+    ($($ty:ident),*) => {
+        impl<F, Fut, S, $($ty,)*> Handler<($($ty,)*), S> for F
+        where
+            F: Fn($($ty),*) -> Fut + Send + Sync + 'static,
+            Fut: Future + Send + 'static,
+            Fut::Output: IntoResponse,
+            $($ty: FromRequest<S>,)*
+        {
+            #[allow(non_snake_case, unused_variables)]
+            fn call(
+                &self,
+                request: Request,
+                peer: Option<SocketAddr>,
+                params: Params,
+                state: S,
+                urls: Urls,
+            ) -> BoxFuture<'static, Response> {
+                let parts = RequestParts {
+                    request: &request,
+                    params: &params,
+                    state: &state,
+                    urls: &urls,
+                    peer,
+                };
+                $(
+                    let $ty = match $ty::from_request(&parts) {
+                        Ok(value) => value,
+                        Err(rejection) => {
+                            let response = rejection.into_response();
+                            return Box::pin(async move { response });
+                        }
+                    };
+                )*
+
+                let response = self($($ty),*);
+                Box::pin(async move { response.await.into_response() })
+            }
+        }
+    };
+}
+
+impl_handler!();
+impl_handler!(T1);
+impl_handler!(T1, T2);
+impl_handler!(T1, T2, T3);
+impl_handler!(T1, T2, T3, T4);
+impl_handler!(T1, T2, T3, T4, T5);
+impl_handler!(T1, T2, T3, T4, T5, T6);
+
+// a handler with its argument types erased, as stored by the router
+pub(super) trait ErasedHandler<S>: Send + Sync {
+    fn call(
+        &self,
+        request: Request,
+        peer: Option<SocketAddr>,
+        params: Params,
+        state: S,
+        urls: Urls,
+    ) -> BoxFuture<'static, Response>;
+}
+
+struct Erased<H, T>(H, PhantomData<fn() -> T>);
+
+impl<H, T, S> ErasedHandler<S> for Erased<H, T>
+where
+    H: Handler<T, S>,
+{
+    fn call(
+        &self,
+        request: Request,
+        peer: Option<SocketAddr>,
+        params: Params,
+        state: S,
+        urls: Urls,
+    ) -> BoxFuture<'static, Response> {
+        self.0.call(request, peer, params, state, urls)
+    }
+}
+
+pub(super) fn erase<H, T, S>(handler: H) -> Box<dyn ErasedHandler<S>>
+where
+    H: Handler<T, S>,
+    T: 'static,
+    S: 'static,
+{
+    Box::new(Erased(handler, PhantomData))
+}
@@ -0,0 +1,250 @@
+use std::{cmp::Reverse, collections::HashMap, net::SocketAddr};
+
+use anyhow::{bail, Result};
+
+use crate::http::message::{
+    message::Version,
+    request::Request,
+    response::{ClientError, HttpError, IntoResponse, Response, Status},
+};
+
+use super::{BoxFuture, Router};
+
+// selects a router by the Host header of a request
+// exact names win over wildcards, *.example.com matches every subdomain of example.com
+// (but not example.com itself), longer wildcards win over shorter ones
+// requests for unknown hosts go to the fallback router, or are answered with 421 if there is none
+// names are compared case-insensitively and without port
+pub struct VirtualHosts<S> {
+    exact: HashMap<String, Router<S>>,
+    // suffixes including the leading dot, longest first
+    wildcards: Vec<(String, Router<S>)>,
+    fallback: Option<Router<S>>,
+}
+
+impl<S> Default for VirtualHosts<S> {
+    fn default() -> Self {
+        Self {
+            exact: HashMap::new(),
+            wildcards: Vec::new(),
+            fallback: None,
+        }
+    }
+}
+
+impl<S: Clone + Send + Sync + 'static> VirtualHosts<S> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // serves pattern (files.internal or *.internal) with router, see parse_host_pattern
+    pub fn add(&mut self, pattern: &str, router: Router<S>) -> Result<()> {
+        let (name, wildcard) = parse_host_pattern(pattern)?;
+
+        if wildcard {
+            if self.wildcards.iter().any(|(s, _)| *s == name) {
+                bail!("host {} already added", pattern);
+            }
+            self.wildcards.push((name, router));
+            self.wildcards.sort_by_key(|(s, _)| Reverse(s.len()));
+        } else {
+            if self.exact.contains_key(&name) {
+                bail!("host {} already added", pattern);
+            }
+            self.exact.insert(name, router);
+        }
+
+        Ok(())
+    }
+
+    // handles requests for hosts that were not added
+    pub fn fallback(&mut self, router: Router<S>) {
+        self.fallback = Some(router);
+    }
+
+    // HTTP/1.1 requires a Host header (RFC 9112, section 3.2), requests without one get 400
+    // HTTP/1.0 clients may not send one, their requests go to the fallback
+    pub fn execute(&self, request: Request) -> BoxFuture<'_, Response> {
+        self.execute_with(request, None)
+    }
+
+    // executes a request received from peer, which handlers can extract with PeerAddr
+    pub fn execute_from(&self, request: Request, peer: SocketAddr) -> BoxFuture<'_, Response> {
+        self.execute_with(request, Some(peer))
+    }
+
+    fn execute_with(&self, request: Request, peer: Option<SocketAddr>) -> BoxFuture<'_, Response> {
+        let host = match request.header("Host") {
+            Some(host) => host_name(host),
+            None if request.start_line.version == Version::Http1_0 => {
+                return match &self.fallback {
+                    Some(router) => router.execute_with(request, peer),
+                    None => Box::pin(async move {
+                        Status::ClientError(ClientError::MisdirectedRequest).into_response()
+                    }),
+                };
+            }
+            None => None,
+        };
+        let Some(host) = host else {
+            let error = HttpError::bad_request("missing or invalid Host header");
+            return Box::pin(async move { error.into_response() });
+        };
+
+        match self.select(&host) {
+            Some(router) => router.execute_with(request, peer),
+            None => Box::pin(async move {
+                Status::ClientError(ClientError::MisdirectedRequest).into_response()
+            }),
+        }
+    }
+
+    fn select(&self, host: &str) -> Option<&Router<S>> {
+        if let Some(router) = self.exact.get(host) {
+            return Some(router);
+        }
+
+        self.wildcards
+            .iter()
+            .find(|(suffix, _)| host.len() > suffix.len() && host.ends_with(suffix.as_str()))
+            .map(|(_, router)| router)
+            .or(self.fallback.as_ref())
+    }
+}
+
+// the lowercased name of a host pattern and whether it is a wildcard,
+// *.internal is the wildcard .internal, files.internal is not a wildcard
+pub fn parse_host_pattern(pattern: &str) -> Result<(String, bool)> {
+    let pattern = pattern.to_ascii_lowercase();
+    let (name, wildcard) = match pattern.strip_prefix('*') {
+        Some(suffix) => (suffix, true),
+        None => (pattern.as_str(), false),
+    };
+    if name.is_empty() || name.contains(['*', ':', '/']) || (wildcard && !name.starts_with('.')) {
+        bail!("invalid host {}", pattern);
+    }
+
+    Ok((name.to_string(), wildcard))
+}
+
+// the lowercased name of a Host header value, without port and trailing dot
+fn host_name(value: &str) -> Option<String> {
+    let value = value.trim();
+    let port = |port: &str| port.chars().all(|c| c.is_ascii_digit());
+    let name = match value.strip_prefix('[') {
+        // IPv6 literal, [::1]:4221
+        Some(rest) => {
+            let end = rest.find(']')? + 2;
+            match &value[end..] {
+                "" => &value[..end],
+                rest if rest.strip_prefix(':').is_some_and(port) => &value[..end],
+                _ => return None,
+            }
+        }
+        None => match value.rsplit_once(':') {
+            Some((name, p)) if port(p) => name,
+            Some(_) => return None,
+            None => value,
+        },
+    };
+    let name = name.strip_suffix('.').unwrap_or(name);
+
+    (!name.is_empty()).then(|| name.to_ascii_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+
+    use crate::http::message::{
+        message::Version,
+        request::{Method, Request, RequestLine},
+        response::Response,
+    };
+
+    use super::{super::extract::State, host_name, Router, VirtualHosts};
+
+    // answers with its name
+    fn named(name: &'static str) -> Router<&'static str> {
+        async fn hello(State(name): State<&'static str>) -> Result<Response> {
+            Ok(Response::ok(HashMap::new(), Some(name.to_string())))
+        }
+
+        let mut router = Router::new(hello, name);
+        router.add(Method::Get, "/".to_string(), hello).unwrap();
+        router
+    }
+
+    async fn execute(hosts: &VirtualHosts<&'static str>, host: Option<&str>) -> Response {
+        execute_version(hosts, host, Version::Http1_1).await
+    }
+
+    async fn execute_version(
+        hosts: &VirtualHosts<&'static str>,
+        host: Option<&str>,
+        version: Version,
+    ) -> Response {
+        let headers = match host {
+            Some(host) => HashMap::from([("Host".to_string(), host.to_string())]),
+            None => HashMap::new(),
+        };
+        let request = Request::new(
+            RequestLine::new(Method::Get, "/".to_string(), version),
+            headers,
+            None,
+        );
+        hosts.execute(request).await
+    }
+
+    #[test]
+    fn host_names() {
+        assert_eq!(host_name("Files.Internal:4221").unwrap(), "files.internal");
+        assert_eq!(host_name("example.com.").unwrap(), "example.com");
+        assert_eq!(host_name("[::1]:4221").unwrap(), "[::1]");
+        assert_eq!(host_name(""), None);
+        assert_eq!(host_name("a:b"), None);
+        assert_eq!(host_name("[::1"), None);
+        assert_eq!(host_name("[::1]junk"), None);
+        assert_eq!(host_name("[::1]:x"), None);
+        assert_eq!(host_name("[::1]").unwrap(), "[::1]");
+    }
+
+    #[tokio::test]
+    async fn virtual_hosts() {
+        let mut hosts = VirtualHosts::new();
+        hosts.add("files.internal", named("files")).unwrap();
+        hosts.add("*.internal", named("internal")).unwrap();
+        hosts.add("*.api.internal", named("api")).unwrap();
+        assert!(hosts.add("FILES.internal", named("files")).is_err());
+        assert!(hosts.add("a*.internal", named("invalid")).is_err());
+        assert!(hosts.add("*internal", named("invalid")).is_err());
+
+        let body = |response: Response| response.body.unwrap();
+        assert_eq!(
+            body(execute(&hosts, Some("files.internal:4221")).await),
+            "files"
+        );
+        assert_eq!(
+            body(execute(&hosts, Some("a.b.internal")).await),
+            "internal"
+        );
+        assert_eq!(body(execute(&hosts, Some("v1.api.internal")).await), "api");
+
+        // no fallback
+        let response = execute(&hosts, Some("internal")).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 421);
+
+        hosts.fallback(named("default"));
+        assert_eq!(body(execute(&hosts, Some("localhost")).await), "default");
+
+        let response = execute(&hosts, None).await;
+        assert_eq!(Into::<u16>::into(response.start_line.status()), 400);
+        // HTTP/1.0 does not require a Host header
+        assert_eq!(
+            body(execute_version(&hosts, None, Version::Http1_0).await),
+            "default"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+use crate::http::message::{request::Request, response::Response};
+
+use super::BoxFuture;
+
+// wraps request handling, a middleware can
+// - inspect or modify the request before passing it on with next.run(request)
+// - short-circuit by returning a response without calling next
+// - post-process the response returned by next.run(request)
+pub trait Middleware: Send + Sync {
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response>;
+
+    // shown in route listings, the type (or function) name unless overridden
+    fn name(&self) -> &'static str {
+        std::any::type_name::<Self>()
+    }
+}
+
+// allows plain functions as middleware:
+// fn log(request: Request, next: Next<'_>) -> BoxFuture<'_, Response>
+impl<F> Middleware for F
+where
+    F: for<'a> Fn(Request, Next<'a>) -> BoxFuture<'a, Response> + Send + Sync,
+{
+    fn call<'a>(&'a self, request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        self(request, next)
+    }
+}
+
+type Endpoint<'a> = Box<dyn FnOnce(Request) -> BoxFuture<'a, Response> + Send + 'a>;
+
+// the remaining middleware chain and the endpoint it ends in
+pub struct Next<'a> {
+    middleware: &'a [Box<dyn Middleware>],
+    endpoint: Endpoint<'a>,
+}
+
+impl<'a> Next<'a> {
+    pub(super) fn new(
+        middleware: &'a [Box<dyn Middleware>],
+        endpoint: impl FnOnce(Request) -> BoxFuture<'a, Response> + Send + 'a,
+    ) -> Self {
+        Self {
+            middleware,
+            endpoint: Box::new(endpoint),
+        }
+    }
+
+    pub fn run(self, request: Request) -> BoxFuture<'a, Response> {
+        match self.middleware.split_first() {
+            Some((first, rest)) => first.call(
+                request,
+                Next {
+                    middleware: rest,
+                    endpoint: self.endpoint,
+                },
+            ),
+            None => (self.endpoint)(request),
+        }
+    }
+}
@@ -0,0 +1,277 @@
+use anyhow::Result;
+use serde_json::{json, Map, Value};
+
+use crate::http::message::response::Status;
+
+use super::{params::Constraint, parse_template, Router, Segment};
+
+// documentation of a route, added with Route::summary, Route::request_body and Route::response
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Docs {
+    pub summary: Option<String>,
+    pub request_body: Option<Content>,
+    // responses without content have no body
+    pub responses: Vec<(Status, Option<Content>)>,
+}
+
+// a body of the given media type, its schema is a JSON schema
+#[derive(Debug, Clone, PartialEq)]
+pub struct Content {
+    pub media_type: String,
+    pub schema: Value,
+}
+
+impl<S: Clone + Send + Sync + 'static> Router<S> {
+    // an OpenAPI 3.1 document describing every route listed by Router::routes
+    // path parameters are documented with a schema derived from their constraint,
+    // catch-alls become a single parameter as OpenAPI cannot express multi-segment parameters
+    // guarded routes sharing method and template are merged into one operation, request bodies
+    // and responses of every variant are listed, the first route's summary and name are kept
+    // fails if a template joined from a nesting prefix and a nested route is invalid
+    pub fn openapi(&self, title: &str, version: &str) -> Result<Value> {
+        let mut paths = Map::new();
+        for route in self.routes() {
+            let segments = parse_template(&route.template)?;
+
+            let mut path = String::new();
+            let mut parameters = Vec::new();
+            for segment in &segments {
+                path.push('/');
+                match segment {
+                    Segment::Static(s) => path.push_str(s),
+                    Segment::Param(name, constraint) => {
+                        path.push_str(&format!("{{{}}}", name));
+                        parameters.push(json!({
+                            "name": name,
+                            "in": "path",
+                            "required": true,
+                            "schema": schema(constraint.as_ref()),
+                        }));
+                    }
+                    Segment::CatchAll(name) => {
+                        path.push_str(&format!("{{{}}}", name));
+                        parameters.push(json!({
+                            "name": name,
+                            "in": "path",
+                            "required": true,
+                            "description": "the rest of the path, may contain /",
+                            "schema": { "type": "string" },
+                        }));
+                    }
+                }
+            }
+
+            let mut operation = Map::new();
+            if let Some(summary) = route.docs.summary {
+                operation.insert("summary".to_string(), json!(summary));
+            }
+            if let Some(name) = route.name {
+                operation.insert("operationId".to_string(), json!(name));
+            }
+            if !parameters.is_empty() {
+                operation.insert("parameters".to_string(), json!(parameters));
+            }
+            if let Some(body) = route.docs.request_body {
+                operation.insert(
+                    "requestBody".to_string(),
+                    json!({ "required": true, "content": content(body) }),
+                );
+            }
+
+            let mut responses = Map::new();
+            for (status, body) in route.docs.responses {
+                let code: u16 = status.into();
+                let description: String = status.into();
+                let response = responses
+                    .entry(code.to_string())
+                    .or_insert_with(|| json!({ "description": description }));
+                if let Some(body) = body {
+                    response["content"][&body.media_type] = json!({ "schema": body.schema });
+                }
+            }
+            if !responses.is_empty() {
+                operation.insert("responses".to_string(), Value::Object(responses));
+            }
+
+            let method = String::from(route.method).to_lowercase();
+            let item = paths.entry(path).or_insert_with(|| json!({}));
+            match item.get_mut(&method) {
+                Some(Value::Object(existing)) => merge(existing, operation),
+                _ => item[method] = Value::Object(operation),
+            }
+        }
+
+        Ok(json!({
+            "openapi": "3.1.0",
+            "info": { "title": title, "version": version },
+            "paths": paths,
+        }))
+    }
+}
+
+// adds the parts of other missing from into, objects are merged and other values of into are kept
+fn merge(into: &mut Map<String, Value>, other: Map<String, Value>) {
+    for (key, value) in other {
+        match (into.get_mut(&key), value) {
+            (Some(Value::Object(existing)), Value::Object(value)) => merge(existing, value),
+            (Some(_), _) => {}
+            (None, value) => {
+                into.insert(key, value);
+            }
+        }
+    }
+}
+
+fn content(body: Content) -> Value {
+    let mut content = Map::new();
+    content.insert(body.media_type, json!({ "schema": body.schema }));
+    Value::Object(content)
+}
+
+fn schema(constraint: Option<&Constraint>) -> Value {
+    match constraint {
+        Some(Constraint::U64) => json!({ "type": "integer", "format": "int64", "minimum": 0 }),
+        Some(Constraint::I64) => json!({ "type": "integer", "format": "int64" }),
+        Some(Constraint::Uuid) => json!({ "type": "string", "format": "uuid" }),
+        Some(Constraint::Alpha) => json!({ "type": "string", "pattern": "^[A-Za-z]+$" }),
+        // already anchored
+        Some(Constraint::Regex(regex)) => json!({ "type": "string", "pattern": regex.as_str() }),
+        None => json!({ "type": "string" }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use anyhow::Result;
+    use serde_json::json;
+
+    use crate::http::{
+        message::{
+            request::Method,
+            response::{ClientError, Response, Status, Successful},
+        },
+        router::{ContentType, Route, Router},
+    };
+
+    async fn ok() -> Result<Response> {
+        Ok(Response::ok(HashMap::new(), None))
+    }
+
+    #[test]
+    fn openapi() {
+        let mut files = Router::new(ok, ());
+        files
+            .route(
+                Route::new(Method::Post, "/{*path}".to_string(), ok)
+                    .name("create_file")
+                    .summary("creates a file")
+                    .request_body("application/octet-stream", json!({ "type": "string" }))
+                    .response(
+                        Status::Successful(Successful::Created),
+                        "text/plain",
+                        json!({ "type": "string" }),
+                    )
+                    .empty_response(Status::ClientError(ClientError::NotFound)),
+            )
+            .unwrap();
+
+        let mut router = Router::new(ok, ());
+        router
+            .add(Method::Get, "/items/{id:u64}".to_string(), ok)
+            .unwrap();
+        router.nest("/files", files).unwrap();
+
+        let document = router.openapi("test", "1.0").unwrap();
+        assert_eq!(
+            document,
+            json!({
+                "openapi": "3.1.0",
+                "info": { "title": "test", "version": "1.0" },
+                "paths": {
+                    "/files/{path}": {
+                        "post": {
+                            "summary": "creates a file",
+                            "operationId": "create_file",
+                            "parameters": [{
+                                "name": "path",
+                                "in": "path",
+                                "required": true,
+                                "description": "the rest of the path, may contain /",
+                                "schema": { "type": "string" },
+                            }],
+                            "requestBody": {
+                                "required": true,
+                                "content": {
+                                    "application/octet-stream": { "schema": { "type": "string" } },
+                                },
+                            },
+                            "responses": {
+                                "201": {
+                                    "description": "Created",
+                                    "content": { "text/plain": { "schema": { "type": "string" } } },
+                                },
+                                "404": { "description": "Not Found" },
+                            },
+                        },
+                    },
+                    "/items/{id}": {
+                        "get": {
+                            "parameters": [{
+                                "name": "id",
+                                "in": "path",
+                                "required": true,
+                                "schema": { "type": "integer", "format": "int64", "minimum": 0 },
+                            }],
+                        },
+                    },
+                },
+            })
+        );
+    }
+
+    #[test]
+    fn guarded_routes() {
+        let mut router = Router::new(ok, ());
+        for media_type in ["application/json", "text/plain"] {
+            router
+                .route(
+                    Route::new(Method::Post, "/items".to_string(), ok)
+                        .guard(ContentType(media_type))
+                        .summary(media_type)
+                        .request_body(media_type, json!({ "type": "string" }))
+                        .response(
+                            Status::Successful(Successful::Created),
+                            media_type,
+                            json!({ "type": "string" }),
+                        ),
+                )
+                .unwrap();
+        }
+
+        let document = router.openapi("test", "1.0").unwrap();
+        assert_eq!(
+            document["paths"]["/items"]["post"],
+            json!({
+                "summary": "application/json",
+                "requestBody": {
+                    "required": true,
+                    "content": {
+                        "application/json": { "schema": { "type": "string" } },
+                        "text/plain": { "schema": { "type": "string" } },
+                    },
+                },
+                "responses": {
+                    "201": {
+                        "description": "Created",
+                        "content": {
+                            "application/json": { "schema": { "type": "string" } },
+                            "text/plain": { "schema": { "type": "string" } },
+                        },
+                    },
+                },
+            })
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use regex::Regex;
+use uuid::Uuid;
+
+// values of the {name} segments of the matched route
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Params(pub(super) HashMap<String, (String, ParamValue)>);
+
+// a parameter as parsed by the constraint of its route, unconstrained parameters are strings
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParamValue {
+    Str(String),
+    U64(u64),
+    I64(i64),
+    Uuid(Uuid),
+}
+
+impl Params {
+    // the raw segment(s) as they appeared in the path
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.0.get(name).map(|(raw, _)| raw.as_str())
+    }
+
+    pub fn value(&self, name: &str) -> Option<&ParamValue> {
+        self.0.get(name).map(|(_, value)| value)
+    }
+
+    pub fn u64(&self, name: &str) -> Option<u64> {
+        match self.value(name)? {
+            ParamValue::U64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn i64(&self, name: &str) -> Option<i64> {
+        match self.value(name)? {
+            ParamValue::I64(v) => Some(*v),
+            _ => None,
+        }
+    }
+
+    pub fn uuid(&self, name: &str) -> Option<Uuid> {
+        match self.value(name)? {
+            ParamValue::Uuid(v) => Some(*v),
+            _ => None,
+        }
+    }
+}
+
+// restricts which segments a {name:constraint} parameter matches
+// u64, i64, uuid and alpha are built in, anything else is a regex for the whole segment
+#[derive(Debug, Clone)]
+pub(super) enum Constraint {
+    U64,
+    I64,
+    Uuid,
+    Alpha,
+    Regex(Regex),
+}
+
+impl PartialEq for Constraint {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Constraint::Regex(a), Constraint::Regex(b)) => a.as_str() == b.as_str(),
+            (a, b) => std::mem::discriminant(a) == std::mem::discriminant(b),
+        }
+    }
+}
+
+impl Constraint {
+    pub fn parse(spec: &str) -> Result<Self> {
+        let constraint = match spec {
+            "u64" => Constraint::U64,
+            "i64" => Constraint::I64,
+            "uuid" => Constraint::Uuid,
+            "alpha" => Constraint::Alpha,
+            // anchored, a constraint always applies to the whole segment
+            _ => Constraint::Regex(
+                Regex::new(&format!("^(?:{})$", spec))
+                    .with_context(|| format!("invalid constraint {}", spec))?,
+            ),
+        };
+
+        Ok(constraint)
+    }
+
+    // returns the parsed value if the segment satisfies the constraint
+    pub fn check(&self, segment: &str) -> Option<ParamValue> {
+        match self {
+            // str::parse also accepts a leading +
+            Constraint::U64 => digits(segment)
+                .then(|| segment.parse().ok())
+                .flatten()
+                .map(ParamValue::U64),
+            Constraint::I64 => digits(segment.strip_prefix('-').unwrap_or(segment))
+                .then(|| segment.parse().ok())
+                .flatten()
+                .map(ParamValue::I64),
+            Constraint::Uuid => Uuid::parse_str(segment).ok().map(ParamValue::Uuid),
+            Constraint::Alpha => segment
+                .chars()
+                .all(|c| c.is_ascii_alphabetic())
+                .then(|| ParamValue::Str(segment.to_string())),
+            Constraint::Regex(regex) => regex
+                .is_match(segment)
+                .then(|| ParamValue::Str(segment.to_string())),
+        }
+    }
+}
+
+fn digits(segment: &str) -> bool {
+    !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Constraint, ParamValue};
+
+    #[test]
+    fn constraints() {
+        assert_eq!(
+            Constraint::parse("u64").unwrap().check("42"),
+            Some(ParamValue::U64(42))
+        );
+        assert_eq!(Constraint::parse("u64").unwrap().check("-1"), None);
+        assert_eq!(Constraint::parse("u64").unwrap().check("+42"), None);
+        assert_eq!(Constraint::parse("i64").unwrap().check("+42"), None);
+        assert_eq!(Constraint::parse("i64").unwrap().check("-"), None);
+        assert_eq!(
+            Constraint::parse("i64").unwrap().check("-1"),
+            Some(ParamValue::I64(-1))
+        );
+        assert!(Constraint::parse("uuid")
+            .unwrap()
+            .check("67e55044-10b1-426f-9247-bb680e5fe0c8")
+            .is_some());
+        assert_eq!(Constraint::parse("alpha").unwrap().check("abc1"), None);
+
+        let slug = Constraint::parse("[a-z-]+").unwrap();
+        assert!(slug.check("hello-world").is_some());
+        // anchored
+        assert!(slug.check("hello world").is_none());
+
+        assert!(Constraint::parse("[a-z").is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use anyhow::{bail, Context, Result};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::http::message::{
+    request::Request,
+    response::{HttpError, IntoResponse, Redirection, Response, ServerError, Status},
+};
+
+use super::{redirect, BoxFuture, Middleware, Next};
+
+// rewrites and redirects applied to the path before routing, as a router layer
+// rules are tried in order against the path (without query), the first matching rule applies:
+// - a redirect answers with its status and the target as Location
+// - a rewrite replaces the path and the rules are tried again on the result
+// targets may refer to the captures of the pattern as $1 or $name
+// the original query is appended to the target unless the rule says otherwise
+//
+// rewrites and redirects to local paths are followed until no rule matches, a path reached twice
+// (or too many steps) is a loop and is answered with 508 instead of sending clients in circles
+#[derive(Clone, Default)]
+pub struct Rules(Vec<Rule>);
+
+#[derive(Clone)]
+pub struct Rule {
+    pattern: Regex,
+    target: String,
+    action: Action,
+    preserve_query: bool,
+}
+
+#[derive(Clone, Copy)]
+enum Action {
+    Rewrite,
+    Redirect(Redirection),
+}
+
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Unchanged,
+    Rewritten(String),
+    Redirect(Redirection, String),
+    Loop,
+}
+
+const MAX_STEPS: usize = 16;
+
+impl Rule {
+    // internal, the router sees target instead of the requested path
+    pub fn rewrite(pattern: &str, target: &str) -> Result<Self> {
+        if !target.starts_with('/') {
+            bail!("rewrite target {} must be a path", target);
+        }
+        Rule::new(pattern, target, Action::Rewrite)
+    }
+
+    // external, status is one of 301, 302, 307 or 308
+    pub fn redirect(pattern: &str, target: &str, status: Redirection) -> Result<Self> {
+        if !matches!(
+            status,
+            Redirection::MovedPermanently
+                | Redirection::Found
+                | Redirection::TemporaryRedirect
+                | Redirection::PermanentRedirect
+        ) {
+            let code: u16 = Status::Redirection(status).into();
+            bail!("unsupported redirect status {}", code);
+        }
+        Rule::new(pattern, target, Action::Redirect(status))
+    }
+
+    fn new(pattern: &str, target: &str, action: Action) -> Result<Self> {
+        Ok(Self {
+            pattern: Regex::new(pattern).with_context(|| format!("invalid pattern {}", pattern))?,
+            target: target.to_string(),
+            action,
+            preserve_query: true,
+        })
+    }
+
+    pub fn preserve_query(mut self, preserve: bool) -> Self {
+        self.preserve_query = preserve;
+        self
+    }
+
+    // the target for path, if the rule matches
+    fn apply(&self, path: &str, query: &str) -> Option<String> {
+        let captures = self.pattern.captures(path)?;
+        let mut target = String::new();
+        captures.expand(&self.target, &mut target);
+
+        if self.preserve_query && !query.is_empty() {
+            target.push(if target.contains('?') { '&' } else { '?' });
+            target.push_str(query);
+        }
+
+        Some(target)
+    }
+}
+
+// a rule as written in the rules file, e.g.
+// { "match": "^/download/(?P<f>.+)$", "redirect": "/files/$f", "status": 301 }
+// { "match": "^/dl/(.+)$", "rewrite": "/files/$1", "preserve_query": false }
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    #[serde(rename = "match")]
+    pattern: String,
+    rewrite: Option<String>,
+    redirect: Option<String>,
+    status: Option<u16>,
+    preserve_query: Option<bool>,
+}
+
+impl Rules {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, rule: Rule) {
+        self.0.push(rule);
+    }
+
+    // a JSON array of rules, see RuleConfig
+    pub fn from_json(json: &str) -> Result<Self> {
+        let configs: Vec<RuleConfig> = serde_json::from_str(json).context("invalid rules")?;
+
+        let mut rules = Rules::new();
+        for config in configs {
+            let rule = match (config.rewrite, config.redirect) {
+                (Some(target), None) if config.status.is_none() => {
+                    Rule::rewrite(&config.pattern, &target)?
+                }
+                (Some(_), None) => bail!("rewrite {} cannot have a status", config.pattern),
+                (None, Some(target)) => {
+                    // 302 like most servers, unless told otherwise
+                    let status = match config.status.unwrap_or(302).try_into()? {
+                        Status::Redirection(status) => status,
+                        _ => bail!("invalid redirect status for {}", config.pattern),
+                    };
+                    Rule::redirect(&config.pattern, &target, status)?
+                }
+                _ => bail!("rule {} needs either rewrite or redirect", config.pattern),
+            };
+
+            rules.add(rule.preserve_query(config.preserve_query.unwrap_or(true)));
+        }
+
+        Ok(rules)
+    }
+
+    fn apply(&self, target: &str) -> Outcome {
+        let mut current = target.to_string();
+        let mut seen = Vec::new();
+        let mut rewritten = None;
+        let mut redirected = None;
+
+        for step in 0..=MAX_STEPS {
+            if step == MAX_STEPS {
+                return Outcome::Loop;
+            }
+
+            let (path, query) = current.split_once('?').unwrap_or((&current, ""));
+            if seen.iter().any(|p| p == path) {
+                return Outcome::Loop;
+            }
+            seen.push(path.to_string());
+
+            let Some((rule, next)) = self
+                .0
+                .iter()
+                .find_map(|rule| Some((rule, rule.apply(path, query)?)))
+            else {
+                break;
+            };
+
+            match rule.action {
+                // after a redirect, rewrites are only followed to find loops
+                Action::Rewrite if redirected.is_none() => rewritten = Some(next.clone()),
+                Action::Rewrite => {}
+                Action::Redirect(status) => {
+                    redirected.get_or_insert((status, next.clone()));
+                    // clients will not come back here for redirects to other servers
+                    if !next.starts_with('/') {
+                        break;
+                    }
+                }
+            }
+            current = next;
+        }
+
+        match (redirected, rewritten) {
+            (Some((status, location)), _) => Outcome::Redirect(status, location),
+            (None, Some(target)) => Outcome::Rewritten(target),
+            (None, None) => Outcome::Unchanged,
+        }
+    }
+}
+
+impl Middleware for Rules {
+    fn call<'a>(&'a self, mut request: Request, next: Next<'a>) -> BoxFuture<'a, Response> {
+        match self.apply(&request.start_line.target) {
+            Outcome::Unchanged => next.run(request),
+            Outcome::Rewritten(target) => {
+                request.start_line.target = target;
+                next.run(request)
+            }
+            Outcome::Redirect(status, location) => {
+                Box::pin(async move { redirect(status, location) })
+            }
+            Outcome::Loop => Box::pin(async {
+                HttpError::new(
+                    Status::ServerError(ServerError::LoopDetected),
+                    "rewrite or redirect loop",
+                )
+                .into_response()
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::message::response::Redirection;
+
+    use super::{Outcome, Rule, Rules};
+
+    #[test]
+    fn rewrites() {
+        let rules = Rules(vec![
+            Rule::rewrite("^/dl/(.+)$", "/download/$1").unwrap(),
+            Rule::rewrite("^/download/(?P<f>.+)$", "/files/${f}?from=download").unwrap(),
+        ]);
+
+        assert_eq!(rules.apply("/files/a.txt"), Outcome::Unchanged);
+        // rewrites are applied until no rule matches
+        assert_eq!(
+            rules.apply("/dl/a.txt?raw=1"),
+            Outcome::Rewritten("/files/a.txt?from=download&raw=1".to_string())
+        );
+    }
+
+    #[test]
+    fn redirects() {
+        let rules = Rules(vec![
+            Rule::redirect("^/old/(.+)$", "/new/$1", Redirection::MovedPermanently)
+                .unwrap()
+                .preserve_query(false),
+            Rule::redirect("^/new/(.+)$", "/newer/$1", Redirection::Found).unwrap(),
+            Rule::redirect("^/away$", "https://example.com/", Redirection::Found).unwrap(),
+        ]);
+
+        // the first redirect is sent, the client follows the rest
+        assert_eq!(
+            rules.apply("/old/a?x=1"),
+            Outcome::Redirect(Redirection::MovedPermanently, "/new/a".to_string())
+        );
+        assert_eq!(
+            rules.apply("/new/a?x=1"),
+            Outcome::Redirect(Redirection::Found, "/newer/a?x=1".to_string())
+        );
+        assert_eq!(
+            rules.apply("/away"),
+            Outcome::Redirect(Redirection::Found, "https://example.com/".to_string())
+        );
+
+        assert!(Rule::redirect("^/a$", "/b", Redirection::SeeOther).is_err());
+        assert!(Rule::rewrite("^/a$", "b").is_err());
+    }
+
+    #[test]
+    fn loops() {
+        let redirects = Rules(vec![
+            Rule::redirect("^/a$", "/b", Redirection::Found).unwrap(),
+            Rule::rewrite("^/b$", "/c").unwrap(),
+            Rule::redirect("^/c$", "/a", Redirection::Found).unwrap(),
+        ]);
+        assert_eq!(redirects.apply("/a"), Outcome::Loop);
+
+        // never the same path, but never done either
+        let growing = Rules(vec![Rule::rewrite("^/(.*)$", "/x/$1").unwrap()]);
+        assert_eq!(growing.apply("/a"), Outcome::Loop);
+    }
+
+    #[test]
+    fn from_json() {
+        let rules = Rules::from_json(
+            r#"[
+                { "match": "^/download/(?P<f>.+)$", "redirect": "/files/$f", "status": 308 },
+                { "match": "^/dl/(.+)$", "rewrite": "/files/$1", "preserve_query": false }
+            ]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            rules.apply("/download/a.txt"),
+            Outcome::Redirect(Redirection::PermanentRedirect, "/files/a.txt".to_string())
+        );
+        assert_eq!(
+            rules.apply("/dl/a.txt?x=1"),
+            Outcome::Rewritten("/files/a.txt".to_string())
+        );
+
+        assert!(Rules::from_json(r#"[{ "match": "^/a$" }]"#).is_err());
+        assert!(
+            Rules::from_json(r#"[{ "match": "^/a$", "redirect": "/b", "status": 200 }]"#).is_err()
+        );
+        assert!(
+            Rules::from_json(r#"[{ "match": "^/a$", "rewrite": "/b", "status": 301 }]"#).is_err()
+        );
+        assert!(Rules::from_json(r#"[{ "match": "(", "rewrite": "/b" }]"#).is_err());
+        assert!(Rules::from_json(r#"[{ "match": "^/a$", "rewrite": "/b", "to": "/c" }]"#).is_err());
+    }
+}
@@ -0,0 +1,567 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::{bail, Result};
+
+use crate::http::message::request::Method;
+
+use super::{params::Constraint, ParamValue, Params, Segment};
+
+// prefix tree keyed on path segments
+// every node holds the endpoints registered for the path leading to it
+//
+// parameter names are not part of the tree, /files/{a} and /files/{b} share the same node.
+// registering both for the same method is therefore reported as ambiguous
+// parameters with different constraints get their own nodes, kept in the order they are tried in
+pub(super) struct Node<T> {
+    statics: HashMap<String, Node<T>>,
+    params: Vec<(Option<Constraint>, Node<T>)>,
+    catch_all: Option<Box<Node<T>>>,
+    endpoints: HashMap<Method, Endpoint<T>>,
+}
+
+pub(super) enum Match<'a, T> {
+    Found(&'a T, Params),
+    // the path is known, but not for the requested method
+    MethodNotAllowed(Vec<Method>),
+    // the path and method are known, but every matching endpoint was refused
+    Rejected,
+    NotFound,
+}
+
+// what to look for, passed along while walking the tree
+struct Search<'s, T> {
+    method: Method,
+    ignore_case: bool,
+    accept: &'s dyn Fn(&T) -> bool,
+}
+
+struct Endpoint<T> {
+    template: String,
+    names: Vec<String>,
+    value: T,
+}
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        Self {
+            statics: HashMap::new(),
+            params: Vec::new(),
+            catch_all: None,
+            endpoints: HashMap::new(),
+        }
+    }
+}
+
+impl<T> Node<T> {
+    pub fn insert(
+        &mut self,
+        template: &str,
+        segments: &[Segment],
+        method: Method,
+        value: T,
+    ) -> Result<()> {
+        // checked before anything is added, a failed insert leaves the tree as it was
+        if let Some(existing) = self
+            .node(segments)
+            .and_then(|node| node.endpoints.get(&method))
+        {
+            let method: String = method.into();
+            if existing.template == template {
+                bail!("endpoint {} {} already registered", method, template);
+            }
+            bail!(
+                "endpoint {} {} is ambiguous with {}",
+                method,
+                template,
+                existing.template
+            );
+        }
+
+        let mut node = self;
+        let mut names = Vec::new();
+        for segment in segments {
+            node = match segment {
+                Segment::Static(s) => node.statics.entry(s.clone()).or_default(),
+                Segment::Param(name, constraint) => {
+                    names.push(name.clone());
+                    node.param_child(constraint)
+                }
+                Segment::CatchAll(name) => {
+                    names.push(name.clone());
+                    node.catch_all.get_or_insert_with(Box::default)
+                }
+            };
+        }
+
+        node.endpoints.insert(
+            method,
+            Endpoint {
+                template: template.to_string(),
+                names,
+                value,
+            },
+        );
+
+        Ok(())
+    }
+
+    // the node the segments lead to, if it exists
+    fn node(&self, segments: &[Segment]) -> Option<&Node<T>> {
+        let mut node = self;
+        for segment in segments {
+            node = match segment {
+                Segment::Static(s) => node.statics.get(s)?,
+                Segment::Param(_, constraint) => {
+                    let (_, child) = node.params.iter().find(|(c, _)| c == constraint)?;
+                    child
+                }
+                Segment::CatchAll(_) => node.catch_all.as_deref()?,
+            };
+        }
+        Some(node)
+    }
+
+    // the value registered for exactly this template and method
+    pub fn get_mut(
+        &mut self,
+        template: &str,
+        segments: &[Segment],
+        method: Method,
+    ) -> Option<&mut T> {
+        let mut node = self;
+        for segment in segments {
+            node = match segment {
+                Segment::Static(s) => node.statics.get_mut(s)?,
+                Segment::Param(_, constraint) => {
+                    let (_, child) = node.params.iter_mut().find(|(c, _)| c == constraint)?;
+                    child
+                }
+                Segment::CatchAll(_) => node.catch_all.as_deref_mut()?,
+            };
+        }
+
+        node.endpoints
+            .get_mut(&method)
+            .filter(|e| e.template == template)
+            .map(|e| &mut e.value)
+    }
+
+    // every registered endpoint as (template, method, value), in no particular order
+    pub fn endpoints(&self) -> Vec<(&str, Method, &T)> {
+        let mut endpoints = Vec::new();
+        self.collect(&mut endpoints);
+        endpoints
+    }
+
+    fn collect<'a>(&'a self, endpoints: &mut Vec<(&'a str, Method, &'a T)>) {
+        endpoints.extend(
+            self.endpoints
+                .iter()
+                .map(|(method, e)| (e.template.as_str(), *method, &e.value)),
+        );
+        for child in self.statics.values() {
+            child.collect(endpoints);
+        }
+        for (_, child) in &self.params {
+            child.collect(endpoints);
+        }
+        if let Some(child) = &self.catch_all {
+            child.collect(endpoints);
+        }
+    }
+
+    fn param_child(&mut self, constraint: &Option<Constraint>) -> &mut Node<T> {
+        let position = match self.params.iter().position(|(c, _)| c == constraint) {
+            Some(position) => position,
+            None => {
+                // built-in constraints first, then regexes in registration order, unconstrained last
+                let position = self
+                    .params
+                    .iter()
+                    .take_while(|(c, _)| rank(c) <= rank(constraint))
+                    .count();
+                self.params
+                    .insert(position, (constraint.clone(), Node::default()));
+                position
+            }
+        };
+
+        &mut self.params[position].1
+    }
+
+    // walks the tree once per path segment, collecting parameters on the way
+    // with ignore_case, static segments match regardless of ASCII case (exact matches are tried first)
+    // endpoints refused by accept are skipped as if they did not match
+    pub fn lookup(
+        &self,
+        path: &str,
+        method: Method,
+        ignore_case: bool,
+        accept: &dyn Fn(&T) -> bool,
+    ) -> Match<'_, T> {
+        let Some(path) = path.strip_prefix('/') else {
+            return Match::NotFound;
+        };
+        let parts: Vec<&str> = path.split('/').collect();
+        let mut captured = Vec::new();
+        let search = Search {
+            method,
+            ignore_case,
+            accept,
+        };
+
+        let mut rejected = false;
+        let Some(endpoint) = self.find(&parts, &search, &mut captured, &mut rejected) else {
+            if rejected {
+                return Match::Rejected;
+            }
+
+            let mut allowed = Vec::new();
+            self.allowed(&parts, ignore_case, &mut allowed);
+            if allowed.is_empty() {
+                return Match::NotFound;
+            }
+
+            allowed.sort();
+            allowed.dedup();
+            return Match::MethodNotAllowed(allowed);
+        };
+
+        let params = endpoint
+            .names
+            .iter()
+            .zip(captured)
+            .map(|(name, (raw, value))| (name.clone(), (raw.into_owned(), value)))
+            .collect();
+
+        Match::Found(&endpoint.value, Params(params))
+    }
+
+    // static children are tried before the parameter child and the parameter child
+    // before the catch-all, this makes the result independent of registration order
+    fn find<'a, 'p>(
+        &'a self,
+        parts: &[&'p str],
+        search: &Search<'_, T>,
+        captured: &mut Vec<(Cow<'p, str>, ParamValue)>,
+        rejected: &mut bool,
+    ) -> Option<&'a Endpoint<T>> {
+        let Some((part, rest)) = parts.split_first() else {
+            return self.accepted(search, rejected);
+        };
+
+        for child in self.static_children(part, search.ignore_case) {
+            if let Some(found) = child.find(rest, search, captured, rejected) {
+                return Some(found);
+            }
+        }
+
+        for (constraint, child) in &self.params {
+            let Some(value) = check(constraint, part) else {
+                continue;
+            };
+            captured.push((Cow::Borrowed(part), value));
+            if let Some(found) = child.find(rest, search, captured, rejected) {
+                return Some(found);
+            }
+            captured.pop();
+        }
+
+        if let Some(child) = &self.catch_all {
+            if !part.is_empty() {
+                if let Some(found) = child.accepted(search, rejected) {
+                    let rest = parts.join("/");
+                    captured.push((Cow::Owned(rest.clone()), ParamValue::Str(rest)));
+                    return Some(found);
+                }
+            }
+        }
+
+        None
+    }
+
+    fn accepted(&self, search: &Search<'_, T>, rejected: &mut bool) -> Option<&Endpoint<T>> {
+        let endpoint = self.endpoints.get(&search.method)?;
+        if !(search.accept)(&endpoint.value) {
+            *rejected = true;
+            return None;
+        }
+
+        Some(endpoint)
+    }
+
+    // collects the methods of every route matching the path
+    fn allowed(&self, parts: &[&str], ignore_case: bool, allowed: &mut Vec<Method>) {
+        let Some((part, rest)) = parts.split_first() else {
+            allowed.extend(self.endpoints.keys());
+            return;
+        };
+
+        for child in self.static_children(part, ignore_case) {
+            child.allowed(rest, ignore_case, allowed);
+        }
+        for (constraint, child) in &self.params {
+            if check(constraint, part).is_some() {
+                child.allowed(rest, ignore_case, allowed);
+            }
+        }
+        if let Some(child) = &self.catch_all {
+            if !part.is_empty() {
+                allowed.extend(child.endpoints.keys());
+            }
+        }
+    }
+
+    // the static child for part, followed by those matching it case-insensitively
+    fn static_children<'a, 'p>(
+        &'a self,
+        part: &'p str,
+        ignore_case: bool,
+    ) -> impl Iterator<Item = &'a Node<T>> + 'p
+    where
+        'a: 'p,
+    {
+        let exact = self.statics.get(part);
+        // only scans the children when asked to, exact lookups stay a single hash lookup
+        let similar = ignore_case
+            .then(|| {
+                self.statics
+                    .iter()
+                    .filter(move |(s, _)| *s != part && s.eq_ignore_ascii_case(part))
+                    .map(|(_, child)| child)
+            })
+            .into_iter()
+            .flatten();
+
+        exact.into_iter().chain(similar)
+    }
+}
+
+fn check(constraint: &Option<Constraint>, part: &str) -> Option<ParamValue> {
+    if part.is_empty() {
+        return None;
+    }
+
+    match constraint {
+        Some(constraint) => constraint.check(part),
+        None => Some(ParamValue::Str(part.to_string())),
+    }
+}
+
+fn rank(constraint: &Option<Constraint>) -> u8 {
+    match constraint {
+        Some(Constraint::U64) => 0,
+        Some(Constraint::I64) => 1,
+        Some(Constraint::Uuid) => 2,
+        Some(Constraint::Alpha) => 3,
+        Some(Constraint::Regex(_)) => 4,
+        None => 5,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::{
+        message::request::Method,
+        router::{parse_template, ParamValue, Params},
+    };
+
+    use super::{Match, Node};
+
+    fn found(tree: &Node<usize>, path: &str) -> Option<(usize, Params)> {
+        match tree.lookup(path, Method::Get, false, &|_| true) {
+            Match::Found(value, params) => Some((*value, params)),
+            _ => None,
+        }
+    }
+
+    fn tree(routes: &[&str]) -> Node<usize> {
+        let mut tree = Node::default();
+        for (i, route) in routes.iter().enumerate() {
+            tree.insert(route, &parse_template(route).unwrap(), Method::Get, i)
+                .unwrap();
+        }
+        tree
+    }
+
+    #[test]
+    fn lookup() {
+        let tree = tree(&["/", "/files/{file}", "/users/{id}/posts/{post}"]);
+
+        assert_eq!(found(&tree, "/").unwrap().0, 0);
+        let (value, params) = found(&tree, "/users/1/posts/2").unwrap();
+        assert_eq!(value, 2);
+        assert_eq!(params.get("id"), Some("1"));
+        assert_eq!(params.get("post"), Some("2"));
+
+        assert!(found(&tree, "/files/").is_none());
+        assert!(found(&tree, "/users/1").is_none());
+    }
+
+    #[test]
+    fn backtracks_into_params() {
+        let tree = tree(&["/files/index", "/files/{file}/raw"]);
+        let (value, params) = found(&tree, "/files/index/raw").unwrap();
+
+        assert_eq!(value, 1);
+        assert_eq!(params.get("file"), Some("index"));
+    }
+
+    #[test]
+    fn static_beats_param() {
+        let tree = tree(&["/files/{file}", "/files/index"]);
+
+        assert_eq!(found(&tree, "/files/index").unwrap().0, 1);
+        assert_eq!(found(&tree, "/files/other").unwrap().0, 0);
+    }
+
+    #[test]
+    fn catch_all() {
+        let tree = tree(&["/files/{*path}", "/files/{name}/raw", "/files/index"]);
+
+        let (value, params) = found(&tree, "/files/a/b/c.txt").unwrap();
+        assert_eq!(value, 0);
+        assert_eq!(params.get("path"), Some("a/b/c.txt"));
+
+        assert_eq!(found(&tree, "/files/a").unwrap().0, 0);
+        assert_eq!(found(&tree, "/files/a/raw").unwrap().0, 1);
+        assert_eq!(found(&tree, "/files/index").unwrap().0, 2);
+        assert!(found(&tree, "/files/").is_none());
+        assert!(found(&tree, "/files").is_none());
+    }
+
+    #[test]
+    fn constrained_params() {
+        let tree = tree(&["/items/{slug}", "/items/{s:[a-z-]+}", "/items/{id:u64}"]);
+
+        let (value, params) = found(&tree, "/items/42").unwrap();
+        assert_eq!(value, 2);
+        assert_eq!(params.value("id"), Some(&ParamValue::U64(42)));
+        assert_eq!(params.get("id"), Some("42"));
+
+        assert_eq!(found(&tree, "/items/a-b").unwrap().0, 1);
+        assert_eq!(found(&tree, "/items/A_B").unwrap().0, 0);
+    }
+
+    #[test]
+    fn names_are_per_route() {
+        let tree = tree(&["/files/{file}/raw", "/files/{name}/meta"]);
+
+        let (_, params) = found(&tree, "/files/a/raw").unwrap();
+        assert_eq!(params.get("file"), Some("a"));
+        let (_, params) = found(&tree, "/files/a/meta").unwrap();
+        assert_eq!(params.get("name"), Some("a"));
+    }
+
+    #[test]
+    fn method_not_allowed() {
+        let mut tree = tree(&["/files/index"]);
+        let segments = parse_template("/files/{file}").unwrap();
+        tree.insert("/files/{file}", &segments, Method::Post, 1)
+            .unwrap();
+
+        assert!(matches!(
+            tree.lookup("/files/index", Method::Delete, false, &|_| true),
+            Match::MethodNotAllowed(allowed) if allowed == vec![Method::Get, Method::Post]
+        ));
+        assert!(matches!(
+            tree.lookup("/files/other", Method::Get, false, &|_| true),
+            Match::MethodNotAllowed(allowed) if allowed == vec![Method::Post]
+        ));
+        assert!(matches!(
+            tree.lookup("/users", Method::Get, false, &|_| true),
+            Match::NotFound
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        let mut tree = tree(&["/files/{file}"]);
+        let segments = parse_template("/files/{file}").unwrap();
+
+        assert!(tree
+            .insert("/files/{file}", &segments, Method::Get, 1)
+            .is_err());
+        assert!(tree
+            .insert("/files/{file}", &segments, Method::Post, 1)
+            .is_ok());
+
+        let error = tree
+            .insert(
+                "/files/{name}",
+                &parse_template("/files/{name}").unwrap(),
+                Method::Get,
+                1,
+            )
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "endpoint GET /files/{name} is ambiguous with /files/{file}"
+        );
+
+        // nothing of a failed insert is left behind
+        let before = tree.endpoints().len();
+        assert!(tree
+            .insert(
+                "/files/{name}",
+                &parse_template("/files/{name}").unwrap(),
+                Method::Post,
+                1,
+            )
+            .is_err());
+        assert_eq!(tree.endpoints().len(), before);
+        assert!(matches!(
+            tree.lookup("/files/a", Method::Delete, false, &|_| true),
+            Match::MethodNotAllowed(allowed) if allowed == vec![Method::Get, Method::Post]
+        ));
+    }
+
+    #[test]
+    fn case_insensitive() {
+        let tree = tree(&["/users/{id}", "/Files/index", "/files/Other"]);
+
+        assert!(matches!(
+            tree.lookup("/USERS/Bob", Method::Get, false, &|_| true),
+            Match::NotFound
+        ));
+        let Match::Found(value, params) = tree.lookup("/USERS/Bob", Method::Get, true, &|_| true)
+        else {
+            panic!("no match");
+        };
+        assert_eq!((*value, params.get("id")), (0, Some("Bob")));
+
+        // every similar static is tried
+        assert!(matches!(
+            tree.lookup("/files/index", Method::Get, true, &|_| true),
+            Match::Found(1, _)
+        ));
+        assert!(matches!(
+            tree.lookup("/FILES/OTHER", Method::Get, true, &|_| true),
+            Match::Found(2, _)
+        ));
+        assert!(matches!(
+            tree.lookup("/files/index", Method::Post, true, &|_| true),
+            Match::MethodNotAllowed(allowed) if allowed == vec![Method::Get]
+        ));
+    }
+
+    #[test]
+    fn rejected() {
+        let tree = tree(&["/items/{id:u64}", "/items/{name}", "/files/{*path}"]);
+        let odd = |value: &usize| value % 2 == 1;
+
+        // refused endpoints are backtracked over like non-matching ones
+        assert!(matches!(
+            tree.lookup("/items/7", Method::Get, false, &odd),
+            Match::Found(1, _)
+        ));
+        assert!(matches!(
+            tree.lookup("/files/a", Method::Get, false, &odd),
+            Match::Rejected
+        ));
+        assert!(matches!(
+            tree.lookup("/files/a", Method::Post, false, &odd),
+            Match::MethodNotAllowed(_)
+        ));
+    }
+}
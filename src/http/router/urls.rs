@@ -0,0 +1,149 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::{bail, Result};
+
+use super::Segment;
+
+// the named routes of a router (including those of nested routers, with their prefix)
+// cheap to clone, handlers receive it through the Urls extractor
+#[derive(Clone, Default)]
+pub struct Urls(Arc<HashMap<String, Vec<Segment>>>);
+
+impl Urls {
+    pub(super) fn insert(&mut self, name: &str, segments: Vec<Segment>) -> Result<()> {
+        if self.0.contains_key(name) {
+            bail!("route name {} already in use", name);
+        }
+        // only shared once the router handles requests, no copy is made while building
+        Arc::make_mut(&mut self.0).insert(name.to_string(), segments);
+        Ok(())
+    }
+
+    pub(super) fn iter(&self) -> impl Iterator<Item = (&String, &Vec<Segment>)> {
+        self.0.iter()
+    }
+
+    pub(super) fn contains(&self, name: &str) -> bool {
+        self.0.contains_key(name)
+    }
+
+    // builds the path of the route registered as name, e.g.
+    // url_for("file", &[("file_path", "a b/c.txt")]) for /files/{*file_path} -> /files/a%20b/c.txt
+    // every parameter of the route must be given once and satisfy its constraint,
+    // unknown ones are rejected
+    pub fn url_for(&self, name: &str, params: &[(&str, &str)]) -> Result<String> {
+        let Some(segments) = self.0.get(name) else {
+            bail!("unknown route {}", name);
+        };
+        for (i, (key, _)) in params.iter().enumerate() {
+            if params[..i].iter().any(|(k, _)| k == key) {
+                bail!("parameter {} given twice for route {}", key, name);
+            }
+        }
+        let param = |param: &str| match params.iter().find(|(k, _)| *k == param) {
+            Some((_, v)) if !v.is_empty() => Ok(*v),
+            Some(_) => bail!("parameter {} of route {} is empty", param, name),
+            None => bail!("missing parameter {} for route {}", param, name),
+        };
+
+        let mut used = Vec::new();
+        let mut url = String::new();
+        for segment in segments {
+            url.push('/');
+            match segment {
+                Segment::Static(s) => url.push_str(s),
+                Segment::Param(p, constraint) => {
+                    let value = param(p)?;
+                    if constraint
+                        .as_ref()
+                        .is_some_and(|c| c.check(value).is_none())
+                    {
+                        bail!(
+                            "invalid value {} for parameter {} of route {}",
+                            value,
+                            p,
+                            name
+                        );
+                    }
+                    url.push_str(&encode(value));
+                    used.push(p);
+                }
+                Segment::CatchAll(p) => {
+                    // the separators of a catch-all are kept
+                    let value = param(p)?;
+                    let encoded = value.split('/').map(encode).collect::<Vec<_>>();
+                    url.push_str(&encoded.join("/"));
+                    used.push(p);
+                }
+            }
+        }
+
+        if let Some((unknown, _)) = params.iter().find(|(k, _)| !used.iter().any(|p| p == k)) {
+            bail!("unknown parameter {} for route {}", unknown, name);
+        }
+
+        Ok(url)
+    }
+}
+
+// percent-encodes everything but unreserved characters
+fn encode(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::http::router::parse_template;
+
+    use super::{encode, Urls};
+
+    #[test]
+    fn url_for() {
+        let mut urls = Urls::default();
+        urls.insert("root", parse_template("/").unwrap()).unwrap();
+        urls.insert("item", parse_template("/items/{id:u64}/{slug}").unwrap())
+            .unwrap();
+        urls.insert("file", parse_template("/files/{*path}").unwrap())
+            .unwrap();
+        assert!(urls
+            .insert("root", parse_template("/other").unwrap())
+            .is_err());
+
+        assert_eq!(urls.url_for("root", &[]).unwrap(), "/");
+        assert_eq!(
+            urls.url_for("item", &[("id", "7"), ("slug", "a b/ü")])
+                .unwrap(),
+            "/items/7/a%20b%2F%C3%BC"
+        );
+        assert_eq!(
+            urls.url_for("file", &[("path", "dir/a?.txt")]).unwrap(),
+            "/files/dir/a%3F.txt"
+        );
+
+        assert!(urls.url_for("unknown", &[]).is_err());
+        assert!(urls.url_for("item", &[("id", "7")]).is_err());
+        assert!(urls.url_for("item", &[("id", "x"), ("slug", "a")]).is_err());
+        assert!(urls.url_for("item", &[("id", "7"), ("slug", "")]).is_err());
+        assert!(urls
+            .url_for("item", &[("id", "7"), ("slug", "a"), ("page", "2")])
+            .is_err());
+        assert!(urls
+            .url_for("item", &[("id", "7"), ("slug", "a"), ("id", "8")])
+            .is_err());
+    }
+
+    #[test]
+    fn encoding() {
+        assert_eq!(encode("AZaz09-._~"), "AZaz09-._~");
+        assert_eq!(encode("a b%"), "a%20b%25");
+    }
+}
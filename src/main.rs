@@ -1,35 +1,122 @@
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use http::{
+use codecrafters_http_server::http::{
+    connection::{serve, ConnectionConfig},
     message::{
         message::HttpMessage,
         request::{Method, Request},
-        response::StatusLine,
+        response::{ClientError, HttpError, Status, StatusLine, Successful},
+    },
+    router::{
+        extract::{Body, Headers, Json, State},
+        parse_host_pattern, BoxFuture, Next, Params, Route, Router, Rules, TrailingSlash, Urls,
+        VirtualHosts,
     },
-    router::Router,
 };
+use serde_json::{json, Value};
+use socket2::{Domain, Socket, Type};
 use std::{
     collections::HashMap,
-    path::Path,
-    sync::{Arc, Mutex},
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
-use std::{
-    fs,
-    io::{Read, Write},
-};
-use tokio::io::AsyncReadExt;
 use tokio::{
+    fs,
     io::AsyncWriteExt,
     net::{TcpListener, TcpStream},
+    runtime,
+    signal::unix::{signal, SignalKind},
+    sync::{mpsc, watch},
+    task::JoinSet,
+    time::timeout,
 };
 
-mod http;
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
     #[arg(short, long)]
     directory: Option<String>,
+    // serves files from another directory for requests to a host, e.g. --host '*.internal=/srv/files'
+    // other hosts are served from --directory
+    #[arg(long = "host", value_name = "PATTERN=DIRECTORY", value_parser = parse_host)]
+    hosts: Vec<(String, String)>,
+    // prints the route table and exits
+    #[arg(long)]
+    routes: bool,
+    // where the OpenAPI document describing all routes is served
+    #[arg(long, default_value = "/openapi.json", value_parser = parse_openapi)]
+    openapi: String,
+    // a JSON file with rewrite and redirect rules applied before routing, see Rules::from_json
+    #[arg(long)]
+    rules: Option<String>,
+    // requests served on a connection before it is closed
+    #[arg(long, default_value_t = 100, value_parser = clap::value_parser!(u64).range(1..))]
+    max_requests: u64,
+    // seconds a connection may wait for its next request
+    #[arg(long, default_value_t = 5)]
+    idle_timeout: u64,
+    // seconds a request that started arriving may go without further bytes
+    #[arg(long, default_value_t = 5)]
+    request_timeout: u64,
+    // pipelined requests read ahead of the response being written
+    #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+    max_pipelined: u64,
+    // handles pipelined requests concurrently instead of one after another
+    #[arg(long)]
+    concurrent: bool,
+    // seconds open connections get to finish their requests on SIGINT or SIGTERM
+    #[arg(long, default_value_t = 10)]
+    grace_period: u64,
+    // addresses to listen on, IPv4 or IPv6, e.g. --bind 0.0.0.0 --bind ::
+    #[arg(
+        long,
+        env = "HTTP_SERVER_BIND",
+        value_delimiter = ',',
+        default_value = "127.0.0.1"
+    )]
+    bind: Vec<IpAddr>,
+    #[arg(long, env = "HTTP_SERVER_PORT", default_value_t = 4221)]
+    port: u16,
+    // IPv6 addresses only accept IPv6 connections, otherwise :: accepts IPv4 connections as well
+    // always the case when IPv4 addresses are bound too, so :: and 0.0.0.0 can share a port
+    #[arg(long, env = "HTTP_SERVER_IPV6_ONLY")]
+    ipv6_only: bool,
+    // sets SO_REUSEPORT, so several instances can listen on the same port
+    #[arg(long, env = "HTTP_SERVER_REUSE_PORT")]
+    reuse_port: bool,
+    // connections waiting to be accepted before the kernel refuses new ones
+    #[arg(
+        long,
+        env = "HTTP_SERVER_BACKLOG",
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(i32).range(1..)
+    )]
+    backlog: i32,
+    // runtime worker threads, one per core by default
+    #[arg(long, env = "HTTP_SERVER_WORKERS", value_parser = clap::value_parser!(u64).range(1..))]
+    workers: Option<u64>,
+    // runs everything on the main thread
+    #[arg(long, env = "HTTP_SERVER_SINGLE_THREADED", conflicts_with = "workers")]
+    single_threaded: bool,
+}
+
+fn parse_host(value: &str) -> Result<(String, String)> {
+    let (pattern, dir) = value
+        .split_once('=')
+        .context("expected PATTERN=DIRECTORY")?;
+    parse_host_pattern(pattern)?;
+    Ok((pattern.to_string(), dir.to_string()))
+}
+
+// a fixed path, the document has no parameters
+fn parse_openapi(value: &str) -> Result<String> {
+    if !value.starts_with('/') || value.contains(['{', '}']) {
+        bail!("expected a path like /openapi.json");
+    }
+    Ok(value.to_string())
 }
 
 struct ApiContext {
@@ -42,135 +129,303 @@ impl ApiContext {
     }
 }
 
-#[tokio::main]
-async fn main() -> std::io::Result<()> {
+fn main() -> Result<()> {
     let args = Args::parse();
-    let ctx = Arc::new(Mutex::new(ApiContext::new(
-        args.directory.unwrap_or_default(),
-    )));
+    if args.routes {
+        return print_routes(&args);
+    }
+
+    let mut runtime = if args.single_threaded {
+        runtime::Builder::new_current_thread()
+    } else {
+        let mut builder = runtime::Builder::new_multi_thread();
+        if let Some(workers) = args.workers {
+            builder.worker_threads(workers as usize);
+        }
+        builder
+    };
+    runtime.enable_all().build()?.block_on(run(args))
+}
+
+async fn run(args: Args) -> Result<()> {
+    let config = ConnectionConfig {
+        max_requests: args.max_requests,
+        idle_timeout: Duration::from_secs(args.idle_timeout),
+        request_timeout: Duration::from_secs(args.request_timeout),
+        max_pipelined: args.max_pipelined as usize,
+        concurrent: args.concurrent,
+    };
+    let grace_period = Duration::from_secs(args.grace_period);
+
+    // every listener hands its connections to the loop below
+    let (sender, mut accepted) = mpsc::channel(1);
+    let mut listeners = JoinSet::new();
+    for ip in &args.bind {
+        let address = SocketAddr::new(*ip, args.port);
+        let listener = listen(address, &args)?;
+        println!("listening on {}", address);
+        listeners.spawn(accept(listener, sender.clone()));
+    }
+    drop(sender);
+
+    // the routing tables are built once and shared by all connections
+    let router = Arc::new(create_hosts(args)?);
+
+    let (shutdown, stopping) = watch::channel(false);
+    let mut connections = JoinSet::new();
+    let signal = shutdown_signal()?;
+    tokio::pin!(signal);
+
+    loop {
+        tokio::select! {
+            Some((socket, peer)) = accepted.recv() => {
+                let router = Arc::clone(&router);
+                connections.spawn(process_socket(socket, peer, router, config, stopping.clone()));
+            }
+            // finished connections are removed as they go
+            Some(_) = connections.join_next() => {}
+            _ = &mut signal => break,
+        }
+    }
+
+    // no new connections, open ones finish the requests they received
+    listeners.shutdown().await;
+    println!(
+        "shutting down, waiting up to {}s for {} connections",
+        grace_period.as_secs(),
+        connections.len()
+    );
+    let _ = shutdown.send(true);
+
+    let drained = timeout(grace_period, async {
+        while connections.join_next().await.is_some() {}
+    })
+    .await;
+    if drained.is_err() {
+        // every dropped connection logs what it was still doing
+        println!(
+            "grace period over, closing {} connections",
+            connections.len()
+        );
+        connections.shutdown().await;
+    }
+
+    Ok(())
+}
+
+fn listen(address: SocketAddr, args: &Args) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    socket.set_reuse_address(true)?;
+    if args.reuse_port {
+        socket.set_reuse_port(true)?;
+    }
+    if address.is_ipv6() {
+        // a dual-stack socket would take the port from the IPv4 listeners
+        let ipv4 = args.bind.iter().any(IpAddr::is_ipv4);
+        socket.set_only_v6(args.ipv6_only || ipv4)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(args.backlog)?;
 
-    let listener = TcpListener::bind("127.0.0.1:4221").await?;
+    TcpListener::from_std(socket.into())
+}
 
+async fn accept(listener: TcpListener, connections: mpsc::Sender<(TcpStream, SocketAddr)>) {
     loop {
-        let context = Arc::clone(&ctx);
+        match listener.accept().await {
+            Ok(connection) => {
+                if connections.send(connection).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                // most likely out of file descriptors, give connections time to close
+                println!("could not accept connection: {}", e);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+        }
+    }
+}
+
+// resolves on SIGINT or SIGTERM, the handlers are registered right away
+fn shutdown_signal() -> Result<impl Future<Output = ()>> {
+    let mut interrupt = signal(SignalKind::interrupt()).context("could not listen for SIGINT")?;
+    let mut terminate = signal(SignalKind::terminate()).context("could not listen for SIGTERM")?;
+
+    Ok(async move {
+        tokio::select! {
+            _ = interrupt.recv() => {}
+            _ = terminate.recv() => {}
+        }
+    })
+}
+
+// every host is served by the same route table, only the directories differ
+fn print_routes(args: &Args) -> Result<()> {
+    let ctx = Arc::new(ApiContext::new(String::new()));
+    let router = create_router(ctx, &args.openapi, &load_rules(args)?)?;
+    println!("{:<8}{:<24}{:<8}MIDDLEWARE", "METHOD", "TEMPLATE", "NAME");
+    for route in router.routes() {
+        let method: String = route.method.into();
+        println!(
+            "{:<8}{:<24}{:<8}{}",
+            method,
+            route.template,
+            route.name.as_deref().unwrap_or("-"),
+            route.middleware.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+fn load_rules(args: &Args) -> Result<Rules> {
+    let Some(path) = &args.rules else {
+        return Ok(Rules::new());
+    };
+
+    let json =
+        std::fs::read_to_string(path).with_context(|| format!("could not read rules {}", path))?;
+    Rules::from_json(&json).with_context(|| format!("could not load rules {}", path))
+}
 
-        let (socket, _) = listener.accept().await?;
-        tokio::spawn(async move {
-            process_socket(socket, &create_router(context)).await;
-        });
+fn create_hosts(args: Args) -> Result<VirtualHosts<Arc<ApiContext>>> {
+    let rules = load_rules(&args)?;
+    let mut hosts = VirtualHosts::new();
+    for (pattern, dir) in args.hosts {
+        let ctx = Arc::new(ApiContext::new(dir));
+        hosts.add(&pattern, create_router(ctx, &args.openapi, &rules)?)?;
     }
+
+    let ctx = Arc::new(ApiContext::new(args.directory.unwrap_or_default()));
+    hosts.fallback(create_router(ctx, &args.openapi, &rules)?);
+
+    Ok(hosts)
 }
 
-fn create_router(ctx: Arc<Mutex<ApiContext>>) -> Router {
-    let mut router = Router::new(Box::new(not_found), ctx);
+fn create_router(
+    ctx: Arc<ApiContext>,
+    openapi: &str,
+    rules: &Rules,
+) -> Result<Router<Arc<ApiContext>>> {
+    let mut router = Router::new(not_found, Arc::clone(&ctx));
+    router.layer(log_request);
+    // after logging, so the requested target is logged
+    router.layer(rules.clone());
+    // /user-agent/ is redirected to /user-agent
+    router.trailing_slash(TrailingSlash::Redirect);
     router
-        .add(
-            Method::Get,
-            "/echo/{yolo}".to_string(),
-            Box::new(handle_echo),
+        .route(
+            Route::new(Method::Get, "/echo/{yolo}".to_string(), handle_echo)
+                .summary("Answers with the given text")
+                .response(Status::Successful(Successful::Ok), "text/plain", text()),
         )
         .expect("could not add endpoint");
 
     router
-        .add(Method::Get, "/".to_string(), Box::new(handle_root))
+        .route(
+            Route::new(Method::Get, "/".to_string(), handle_root)
+                .empty_response(Status::Successful(Successful::Ok)),
+        )
         .expect("could not add endpoint");
 
     router
-        .add(
-            Method::Get,
-            "/user-agent".to_string(),
-            Box::new(handle_useragent),
+        .route(
+            Route::new(Method::Get, "/user-agent".to_string(), handle_useragent)
+                .summary("Answers with the User-Agent header of the request")
+                .response(Status::Successful(Successful::Ok), "text/plain", text())
+                .response(
+                    Status::ClientError(ClientError::BadRequest),
+                    "text/plain",
+                    text(),
+                ),
         )
         .expect("could not add endpoint");
 
     router
-        .add(
-            Method::Get,
-            "/files/{file_path}".to_string(),
-            Box::new(handle_file),
+        .nest("/files", create_files_router(ctx))
+        .expect("could not mount router");
+
+    // describes the routes above, not itself
+    let document = router.openapi(env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"))?;
+    router
+        .add(Method::Get, openapi.to_string(), move || {
+            let document = document.clone();
+            async move { Json(document) }
+        })
+        .with_context(|| format!("could not serve the OpenAPI document at {}", openapi))?;
+
+    Ok(router)
+}
+
+fn create_files_router(ctx: Arc<ApiContext>) -> Router<Arc<ApiContext>> {
+    let mut router = Router::new(not_found, ctx);
+    router
+        .route(
+            Route::new(Method::Get, "/{*file_path}".to_string(), handle_file)
+                .name("file")
+                .summary("Downloads a file")
+                .response(
+                    Status::Successful(Successful::Ok),
+                    "application/octet-stream",
+                    text(),
+                )
+                .empty_response(Status::ClientError(ClientError::NotFound)),
         )
         .expect("could not add endpoint");
 
     router
-        .add(
-            Method::Post,
-            "/files/{file_path}".to_string(),
-            Box::new(handle_create_file),
+        .route(
+            Route::new(
+                Method::Post,
+                "/{*file_path}".to_string(),
+                handle_create_file,
+            )
+            .summary("Creates a file, its URL is returned in the Location header")
+            .request_body("application/octet-stream", text())
+            .empty_response(Status::Successful(Successful::Created)),
         )
         .expect("could not add endpoint");
 
     router
 }
 
-async fn process_socket(mut socket: TcpStream, router: &Router) {
-    println!("accepted new connection");
-    let mut raw_request = String::new();
-
-    // GPT: sometimes we do not receive an EOF marker so we read it one by one...
-    let mut buffer = [0; 1024];
-    let mut request = Vec::new();
-
-    loop {
-        let bytes_read = socket
-            .read(&mut buffer)
-            .await
-            .expect("could not read bytes");
-
-        if bytes_read == 0 {
-            // Connection was closed by the client.
-            println!("bytes read 0");
-            break;
-        }
-
-        // Append the data read to the request buffer
-        request.extend_from_slice(&buffer[..bytes_read]);
+fn text() -> Value {
+    json!({ "type": "string" })
+}
 
-        // Check if we've hit the end of the HTTP headers (double CRLF)
-        if request.windows(4).any(|window| window == b"\r\n\r\n") {
-            break;
-        }
-    }
+async fn process_socket(
+    socket: TcpStream,
+    peer: SocketAddr,
+    router: Arc<VirtualHosts<Arc<ApiContext>>>,
+    config: ConnectionConfig,
+    shutdown: watch::Receiver<bool>,
+) {
+    println!("accepted new connection");
+    let (reader, writer) = socket.into_split();
+    serve(reader, writer, peer, router, config, shutdown).await;
+}
 
-    // Convert the request to a string and print it out
-    if let Ok(request_string) = String::from_utf8(request) {
-        println!("Received request:\n{}", request_string);
-        raw_request = request_string;
-    }
-    // GPT
+fn log_request(request: Request, next: Next<'_>) -> BoxFuture<'_, HttpMessage<StatusLine>> {
+    Box::pin(async move {
+        let method: String = request.start_line.method.into();
+        let target = request.start_line.target.clone();
 
-    // parsing raw request into a struct
-    let request = Request::try_from(raw_request).expect("could not parse request");
+        let response = next.run(request).await;
+        let status: u16 = response.start_line.status().into();
+        println!("{} {} -> {}", method, target, status);
 
-    // TODO:
-    // implement endpoint routing
-    let response_raw = router.execute(
-        request.start_line.method,
-        &request.start_line.target,
-        &request,
-    );
-    // convert into raw response
-    let response = Into::<String>::into(response_raw);
-    println!("{:?}", response);
-
-    socket
-        .write_all(response.as_bytes())
-        .await
-        .expect("could not send response");
+        response
+    })
 }
 
-fn not_found(
-    _: &Request,
-    _: String,
-    _: &Arc<Mutex<ApiContext>>,
-) -> Result<HttpMessage<StatusLine>> {
+async fn not_found() -> Result<HttpMessage<StatusLine>> {
     Ok(HttpMessage::<StatusLine>::not_found())
 }
 
-fn handle_echo(
-    _: &Request,
-    echo: String,
-    _: &Arc<Mutex<ApiContext>>,
-) -> Result<HttpMessage<StatusLine>> {
+async fn handle_echo(params: Params) -> Result<HttpMessage<StatusLine>> {
+    let echo = params.get("yolo").unwrap_or_default();
     let headers = HashMap::from([
         ("Content-Type".to_string(), "text/plain".to_string()),
         ("Content-Length".to_string(), echo.len().to_string()),
@@ -182,23 +437,14 @@ fn handle_echo(
     ))
 }
 
-fn handle_root(
-    _: &Request,
-    _: String,
-    _: &Arc<Mutex<ApiContext>>,
-) -> Result<HttpMessage<StatusLine>> {
+async fn handle_root() -> Result<HttpMessage<StatusLine>> {
     Ok(HttpMessage::<StatusLine>::ok(HashMap::new(), None))
 }
 
-fn handle_useragent(
-    request: &Request,
-    _: String,
-    _: &Arc<Mutex<ApiContext>>,
-) -> Result<HttpMessage<StatusLine>> {
-    let user_agent = request
-        .headers
+async fn handle_useragent(headers: Headers) -> Result<HttpMessage<StatusLine>> {
+    let user_agent = headers
         .get("User-Agent")
-        .context("User-Agent header required")?;
+        .ok_or_else(|| HttpError::bad_request("User-Agent header required"))?;
 
     let headers = HashMap::from([
         ("Content-Type".to_string(), "text/plain".to_string()),
@@ -210,48 +456,66 @@ fn handle_useragent(
     Ok(message)
 }
 
-fn handle_file(
-    _: &Request,
-    file_name: String,
-    ctx: &Arc<Mutex<ApiContext>>,
+async fn handle_file(
+    params: Params,
+    State(ctx): State<Arc<ApiContext>>,
 ) -> Result<HttpMessage<StatusLine>> {
-    let locked_ctx = ctx.lock().expect("could not lock ctx");
-    let path = Path::new(&locked_ctx.dir).join(file_name);
-    let file_path = path.to_str().unwrap();
+    let file_name = params.get("file_path").context("file path required")?;
+    let Some(path) = resolve_file(&ctx.dir, file_name) else {
+        return Ok(HttpMessage::<StatusLine>::not_found());
+    };
 
     // check if file exist
-    let file_handle = std::fs::File::open(file_path);
-    if file_handle.is_err() {
+    let Ok(buffer) = tokio::fs::read_to_string(path).await else {
         return Ok(HttpMessage::<StatusLine>::not_found());
-    }
+    };
 
-    let mut buffer = String::new();
-    let file_content = file_handle.unwrap().read_to_string(&mut buffer).unwrap();
     let headers = HashMap::from([
         (
             "Content-Type".to_string(),
             "application/octet-stream".to_string(),
         ),
-        ("Content-Length".to_string(), file_content.to_string()),
+        ("Content-Length".to_string(), buffer.len().to_string()),
     ]);
 
     Ok(HttpMessage::<StatusLine>::ok(headers, Some(buffer)))
 }
 
-fn handle_create_file(
-    request: &Request,
-    file_name: String,
-    ctx: &Arc<Mutex<ApiContext>>,
+async fn handle_create_file(
+    params: Params,
+    State(ctx): State<Arc<ApiContext>>,
+    urls: Urls,
+    Body(body): Body,
 ) -> Result<HttpMessage<StatusLine>> {
-    let locked_ctx = ctx.lock().expect("could not lock ctx");
-    let path = Path::new(&locked_ctx.dir).join(file_name);
-    let mut file_handle = fs::File::create_new(path).expect("could not create file");
-
-    if let Some(body) = &request.body {
-        file_handle
-            .write_all(body.as_bytes())
-            .expect("could not write to file");
+    let file_name = params.get("file_path").context("file path required")?;
+    let Some(path) = resolve_file(&ctx.dir, file_name) else {
+        return Ok(HttpMessage::<StatusLine>::not_found());
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let mut file_handle = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .await?;
+
+    file_handle.write_all(body.as_bytes()).await?;
+
+    let location = urls.url_for("file", &[("file_path", file_name)])?;
+    let headers = HashMap::from([("Location".to_string(), location)]);
+    Ok(HttpMessage::<StatusLine>::created(headers, None))
+}
+
+// nested paths are allowed, but they must stay inside the served directory
+fn resolve_file(dir: &str, file_name: &str) -> Option<PathBuf> {
+    let relative = Path::new(file_name);
+    if !relative
+        .components()
+        .all(|c| matches!(c, Component::Normal(_)))
+    {
+        return None;
     }
 
-    Ok(HttpMessage::<StatusLine>::created(HashMap::new(), None))
+    Some(Path::new(dir).join(relative))
 }